@@ -1,13 +1,34 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+use crate::wait::InputQueue;
+
+const COM1: u16 = 0x3F8;
 
 lazy_static! {
     pub static ref SERIAL: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
+
+    pub static ref SERIAL_INPUT: InputQueue = InputQueue::new();
+}
+
+// Drains the UART receive buffer into SERIAL_INPUT. Called from the serial
+// interrupt handler.
+pub fn receive_serial() {
+    let mut line_status: Port<u8> = Port::new(COM1 + 5);
+    let mut data: Port<u8> = Port::new(COM1);
+
+    unsafe {
+        // bit 0 of the line status register is set while data is ready
+        while line_status.read() & 1 != 0 {
+            SERIAL_INPUT.push(data.read());
+        }
+    }
 }
 
 pub fn _print(args: ::core::fmt::Arguments) {
@@ -19,6 +40,18 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+// For handlers that may have interrupted whoever holds the port, like the
+// NMI handler. Gives up instead of waiting and returns false if nothing was
+// printed.
+pub fn try_print(args: ::core::fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    match SERIAL.try_lock() {
+        Some(mut serial) => serial.write_fmt(args).is_ok(),
+        None => false,
+    }
+}
+
 #[macro_export]
 macro_rules! dbg_print {
     ($($arg:tt)*) => {
@@ -0,0 +1,460 @@
+use alloc::{vec, vec::Vec};
+use core::mem::size_of;
+use core::ptr;
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::OffsetPageTable;
+
+use crate::memory::paging::{MemoryManager, MemoryRegion, Protection};
+use crate::memory::{AddressSpace, BuddyFrameAllocator, phys_mem_offset};
+use crate::memory::address_space::is_user_address;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const PAGE_SIZE: usize = 4096;
+
+// The user stack sits just below the top of the lower half
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooSmall,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeader,
+    // A segment reaches into kernel space or overlaps another segment
+    BadSegment,
+    MapFailed,
+    StackOverflow,
+    SpawnFailed(i32),
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    // None for empty segments and ones that wrap around
+    fn page_range(&self) -> Option<(u64, u64)> {
+        if self.memsz == 0 {
+            return None;
+        }
+        let page_mask = PAGE_SIZE as u64 - 1;
+        let start = self.vaddr & !page_mask;
+        let end = self.vaddr.checked_add(self.memsz)?.checked_add(page_mask)? & !page_mask;
+        Some((start, end))
+    }
+
+    fn protection(&self) -> Protection {
+        let mut protection = Protection::READ | Protection::USER;
+        if self.flags & PF_W != 0 {
+            protection = protection | Protection::WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            protection = protection | Protection::EXECUTE;
+        }
+        protection
+    }
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(size_of::<T>())?;
+    if end > data.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(data.as_ptr().add(offset) as *const T) })
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let header: ElfHeader = read(data, 0).ok_or(ElfError::TooSmall)?;
+
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if header.ident[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if header.elf_type != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if header.machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if header.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let elf = Elf { data, header };
+        for i in 0..header.phnum as usize {
+            let ph = elf.program_header(i).ok_or(ElfError::BadProgramHeader)?;
+            if ph.segment_type != PT_LOAD {
+                continue;
+            }
+            let file_end = ph.offset.checked_add(ph.filesz).ok_or(ElfError::BadSegment)?;
+            let (_, mem_end) = ph.page_range().ok_or(ElfError::BadSegment)?;
+            if ph.filesz > ph.memsz || file_end > data.len() as u64
+                || mem_end > USER_STACK_TOP - USER_STACK_SIZE as u64 {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.header.entry)
+    }
+
+    pub fn program_header(&self, index: usize) -> Option<ProgramHeader> {
+        if index >= self.header.phnum as usize {
+            return None;
+        }
+        let offset = index.checked_mul(size_of::<ProgramHeader>())?
+            .checked_add(self.header.phoff as usize)?;
+        read(self.data, offset)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.header.phnum as usize).filter_map(move |i| self.program_header(i))
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.program_headers().filter(|ph| ph.segment_type == PT_LOAD)
+    }
+
+    // Virtual address of the program headers once loaded, for AT_PHDR
+    fn phdr_address(&self) -> Option<u64> {
+        if let Some(ph) = self.program_headers().find(|ph| ph.segment_type == PT_PHDR) {
+            return Some(ph.vaddr);
+        }
+        let phoff = self.header.phoff;
+        self.load_segments()
+            .find(|ph| phoff >= ph.offset && phoff < ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + (phoff - ph.offset))
+    }
+}
+
+// Everything mapped for a loaded program
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    pub regions: Vec<MemoryRegion>,
+}
+
+impl LoadedImage {
+    pub fn unload(self, manager: &mut MemoryManager<BuddyFrameAllocator>, mapper: &mut OffsetPageTable) {
+        for region in self.regions {
+            manager.relinquish_address_space(region.start, region.size, mapper)
+                .expect("loaded region is not mapped");
+        }
+    }
+}
+
+// Writes data to addr in the address space behind mapper. The writes go
+// through the physical memory mapping so it doesn't need to be active.
+fn copy_to(mapper: &OffsetPageTable, addr: u64, data: &[u8]) -> Result<(), ElfError> {
+    use x86_64::structures::paging::mapper::MapperAllSizes;
+
+    let mut done = 0;
+    while done < data.len() {
+        let virt = VirtAddr::new(addr + done as u64);
+        let phys = mapper.translate_addr(virt).ok_or(ElfError::MapFailed)?;
+        let chunk = (PAGE_SIZE - u64::from(virt.page_offset()) as usize).min(data.len() - done);
+        unsafe {
+            let dest = (phys_mem_offset() + phys.as_u64()).as_mut_ptr::<u8>();
+            ptr::copy_nonoverlapping(data[done..].as_ptr(), dest, chunk);
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+fn zero(mapper: &OffsetPageTable, addr: u64, len: usize) -> Result<(), ElfError> {
+    let page = [0u8; PAGE_SIZE];
+    let mut done = 0;
+    while done < len {
+        let chunk = PAGE_SIZE.min(len - done);
+        copy_to(mapper, addr + done as u64, &page[..chunk])?;
+        done += chunk;
+    }
+    Ok(())
+}
+
+// Maps the program's segments and a user stack into the address space
+// behind mapper, which doesn't have to be the active one.
+pub fn load(elf: &Elf, argv: &[&str], envp: &[&str],
+            manager: &mut MemoryManager<BuddyFrameAllocator>,
+            mapper: &mut OffsetPageTable) -> Result<LoadedImage, ElfError> {
+    let mut image = LoadedImage {
+        entry: elf.entry(),
+        stack_pointer: VirtAddr::new(USER_STACK_TOP),
+        regions: Vec::new(),
+    };
+
+    let result = load_into(elf, argv, envp, manager, mapper, &mut image);
+    if let Err(error) = result {
+        image.unload(manager, mapper);
+        return Err(error);
+    }
+    Ok(image)
+}
+
+fn load_into(elf: &Elf, argv: &[&str], envp: &[&str],
+             manager: &mut MemoryManager<BuddyFrameAllocator>,
+             mapper: &mut OffsetPageTable, image: &mut LoadedImage) -> Result<(), ElfError> {
+    for ph in elf.load_segments() {
+        let (start, end) = ph.page_range().ok_or(ElfError::BadSegment)?;
+        let overlaps = image.regions.iter().any(|r| {
+            start < r.start.as_u64() + r.size as u64 && r.start.as_u64() < end
+        });
+        // user pages can't share page tables with the kernel
+        let shared = !is_user_address(VirtAddr::new(start))
+            || !is_user_address(VirtAddr::new(end - 1));
+        if overlaps || shared {
+            return Err(ElfError::BadSegment);
+        }
+
+        // mapped writable and not executable until the contents are in,
+        // which are written through the physical memory mapping
+        let size = (end - start) as usize;
+        let region = manager.request_user_address_space_at(VirtAddr::new(start), size, mapper)
+            .map_err(|_| ElfError::MapFailed)?;
+        image.regions.push(region);
+
+        // zeroing the whole range also clears the .bss
+        zero(mapper, start, size)?;
+        let contents = &elf.data[ph.offset as usize..(ph.offset + ph.filesz) as usize];
+        copy_to(mapper, ph.vaddr, contents)?;
+        manager.protect(VirtAddr::new(start), size, ph.protection(), mapper)
+            .map_err(|_| ElfError::MapFailed)?;
+    }
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE as u64);
+    if !is_user_address(stack_bottom) {
+        return Err(ElfError::BadSegment);
+    }
+    let region = manager.request_user_address_space_at(stack_bottom, USER_STACK_SIZE, mapper)
+        .map_err(|_| ElfError::MapFailed)?;
+    image.regions.push(region);
+
+    let (stack_pointer, contents) = build_stack(elf, argv, envp)?;
+    zero(mapper, stack_bottom.as_u64(), USER_STACK_SIZE)?;
+    copy_to(mapper, stack_pointer, &contents)?;
+    image.stack_pointer = VirtAddr::new(stack_pointer);
+    Ok(())
+}
+
+// Lays out the System V initial stack: argc, argv, envp and auxv with the
+// strings they point to above them. Returns the stack pointer and the
+// contents of the stack from there up to USER_STACK_TOP.
+fn build_stack(elf: &Elf, argv: &[&str], envp: &[&str]) -> Result<(u64, Vec<u8>), ElfError> {
+    let strings_size: usize = argv.iter().chain(envp.iter())
+        .map(|s| s.len() + 1)
+        .sum();
+    let strings_start = USER_STACK_TOP - strings_size as u64;
+
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut pointers = Vec::with_capacity(argv.len() + envp.len());
+    for s in argv.iter().chain(envp.iter()) {
+        pointers.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let (argv_ptrs, envp_ptrs) = pointers.split_at(argv.len());
+
+    let auxv = [
+        (AT_PHDR, elf.phdr_address().unwrap_or(0)),
+        (AT_PHENT, size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM, elf.header.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_ENTRY, elf.header.entry),
+        (AT_NULL, 0),
+    ];
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv_ptrs.len() as u64);
+    words.extend(argv_ptrs.iter());
+    words.push(0);
+    words.extend(envp_ptrs.iter());
+    words.push(0);
+    for &(key, value) in auxv.iter() {
+        words.push(key);
+        words.push(value);
+    }
+
+    // rsp must be 16 byte aligned at the entry point
+    let table_size = (words.len() * size_of::<u64>()) as u64;
+    let sp = ((strings_start & !0xf) - table_size) & !0xf;
+    if sp < USER_STACK_TOP - USER_STACK_SIZE as u64 {
+        return Err(ElfError::StackOverflow);
+    }
+
+    let mut contents = vec![0u8; (USER_STACK_TOP - sp) as usize];
+    for (i, word) in words.iter().enumerate() {
+        let offset = i * size_of::<u64>();
+        contents[offset..offset + size_of::<u64>()].copy_from_slice(&word.to_le_bytes());
+    }
+    let strings_offset = (strings_start - sp) as usize;
+    contents[strings_offset..].copy_from_slice(&strings);
+
+    Ok((sp, contents))
+}
+
+// Loads the program into a new address space and creates a proc that
+// starts executing it in user mode. Returns the proc id. The address space
+// is freed when the proc exits.
+pub fn spawn_process(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<usize, ElfError> {
+    use x86_64::instructions::interrupts;
+    use crate::memory::paging::MEMORY_MANAGER;
+    use crate::memory::kernel_stack::KernelStack;
+    use crate::task::{TASK_MANAGER, ENOMEM};
+
+    let elf = Elf::parse(data)?;
+    // allocated while the task manager isn't locked
+    let stack = KernelStack::new().ok_or(ElfError::SpawnFailed(ENOMEM))?;
+    let (space, image) = {
+        let mut guard = MEMORY_MANAGER.lock();
+        let manager = guard.as_mut().ok_or(ElfError::MapFailed)?;
+        let space = AddressSpace::new(&mut manager.frame_allocator)
+            .ok_or(ElfError::MapFailed)?;
+        let mut mapper = unsafe { space.page_table() };
+        match load(&elf, argv, envp, manager, &mut mapper) {
+            Ok(image) => (space, image),
+            Err(error) => {
+                manager.destroy_address_space(space);
+                return Err(error);
+            },
+        }
+    };
+
+    let (entry, stack_pointer) = (image.entry, image.stack_pointer);
+    interrupts::without_interrupts(|| {
+        let mut manager = TASK_MANAGER.write();
+        let proc = manager.spawn_closure(stack, move || unsafe {
+            crate::usermode::enter_user_mode(entry, stack_pointer)
+        }).map_err(ElfError::SpawnFailed)?;
+
+        let mut proc = proc.write();
+        proc.set_address_space(space);
+        Ok(proc.id)
+    })
+}
+
+#[test_case]
+fn test_parse_errors() {
+    assert_eq!(Elf::parse(&[0; 8]).err(), Some(ElfError::TooSmall));
+    let mut data = [0u8; 64];
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadMagic));
+    data[0..4].copy_from_slice(&ELF_MAGIC);
+    data[4] = 1;
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::NotElf64));
+}
+
+#[test_case]
+fn test_malformed_program_headers() {
+    const HEADER_SIZE: usize = size_of::<ElfHeader>();
+    let mut data = [0u8; HEADER_SIZE + size_of::<ProgramHeader>()];
+    data[0..4].copy_from_slice(&ELF_MAGIC);
+    data[4] = ELFCLASS64;
+    data[5] = ELFDATA2LSB;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    data[54..56].copy_from_slice(&(size_of::<ProgramHeader>() as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+    // program headers past the end of the address space
+    data[32..40].copy_from_slice(&(u64::max_value() - 8).to_le_bytes());
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadProgramHeader));
+
+    // an empty segment at address 0
+    data[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    data[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadSegment));
+
+    // one wrapping around the end of the address space
+    let vaddr = HEADER_SIZE + 16;
+    let memsz = HEADER_SIZE + 40;
+    data[vaddr..vaddr + 8].copy_from_slice(&(u64::max_value() - 4095).to_le_bytes());
+    data[memsz..memsz + 8].copy_from_slice(&4096u64.to_le_bytes());
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadSegment));
+}
+
+#[test_case]
+fn test_load_program() {
+    use crate::memory::paging::MEMORY_MANAGER;
+
+    let data = include_bytes!("../test_programs/exit_argc.elf");
+    let elf = Elf::parse(data).expect("could not parse test program");
+    assert_eq!(elf.entry(), VirtAddr::new(0x1000_0040_0000));
+    assert_eq!(elf.load_segments().count(), 3);
+
+    let used_frames = || MEMORY_MANAGER.lock().as_ref()
+        .expect("memory manager not installed")
+        .frame_allocator.used_frames();
+    let used = used_frames();
+
+    let id = spawn_process(data, &["prog", "arg"], &["HOME=/"])
+        .expect("could not spawn test program");
+    // argc + 40 if argv, .data and .bss were set up correctly
+    assert_eq!(crate::task::join(id), Ok(42));
+
+    // exiting freed the whole address space
+    assert_eq!(used_frames(), used);
+}
@@ -0,0 +1,450 @@
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{VirtAddr, PrivilegeLevel};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::gdt;
+
+// Number of instruction bytes captured in a report
+const INSTRUCTION_BYTES: usize = 16;
+const NO_EXPECTATION: usize = usize::max_value();
+
+// Lets tests raise an exception on purpose. When the vector matches, the
+// handler stores its report, skips the faulting instruction and returns.
+static EXPECTED_VECTOR: AtomicUsize = AtomicUsize::new(NO_EXPECTATION);
+static SKIP_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LAST_REPORT: Mutex<Option<ExceptionReport>> = Mutex::new(None);
+
+pub fn set_exception_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
+    // int3 is allowed from user mode
+    idt.breakpoint.set_handler_fn(breakpoint_handler)
+        .set_privilege_level(PrivilegeLevel::Ring3);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+pub fn expect_exception(vector: u8, skip_bytes: usize) {
+    *LAST_REPORT.lock() = None;
+    SKIP_BYTES.store(skip_bytes, Ordering::SeqCst);
+    EXPECTED_VECTOR.store(vector as usize, Ordering::SeqCst);
+}
+
+pub fn take_report() -> Option<ExceptionReport> {
+    LAST_REPORT.lock().take()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    Raw(u64),
+    // Error codes of the form pushed by #TS, #NP, #SS and #GP
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+            ErrorCode::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "{:#x} (index {} in {}", code, (code >> 3) & 0x1fff, table)?;
+                if code & 1 != 0 {
+                    write!(f, ", external event")?;
+                }
+                write!(f, ")")
+            },
+            ErrorCode::PageFault(code) => write!(f, "{:?} ({})", code, PageFaultReason(code)),
+        }
+    }
+}
+
+// Human readable decoding of the page fault error bits
+struct PageFaultReason(PageFaultErrorCode);
+
+impl fmt::Display for PageFaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            write!(f, "protection violation")?;
+        } else {
+            write!(f, "page not present")?;
+        }
+
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            write!(f, " during instruction fetch")?;
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            write!(f, " during write")?;
+        } else {
+            write!(f, " during read")?;
+        }
+
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            write!(f, " in user mode")?;
+        } else {
+            write!(f, " in kernel mode")?;
+        }
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, " (reserved bit set in page table)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionReport {
+    pub vector: u8,
+    pub name: &'static str,
+    pub error_code: Option<ErrorCode>,
+    // CR2 for page faults
+    pub fault_address: Option<VirtAddr>,
+    // Proc whose kernel stack ran into its guard page
+    pub stack_overflow: Option<usize>,
+    pub instruction_pointer: VirtAddr,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: VirtAddr,
+    pub stack_segment: u64,
+    pub instruction_bytes: Option<[u8; INSTRUCTION_BYTES]>,
+}
+
+impl ExceptionReport {
+    fn new(stack_frame: &InterruptStackFrame, vector: u8, name: &'static str,
+           error_code: Option<ErrorCode>) -> Self {
+        ExceptionReport {
+            vector,
+            name,
+            error_code,
+            fault_address: None,
+            stack_overflow: None,
+            instruction_pointer: stack_frame.instruction_pointer,
+            code_segment: stack_frame.code_segment,
+            cpu_flags: stack_frame.cpu_flags,
+            stack_pointer: stack_frame.stack_pointer,
+            stack_segment: stack_frame.stack_segment,
+            instruction_bytes: instruction_bytes(stack_frame.instruction_pointer),
+        }
+    }
+}
+
+impl fmt::Display for ExceptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "EXCEPTION: {} (vector {})", self.name, self.vector)?;
+        if let Some(code) = self.error_code {
+            writeln!(f, "Error code: {}", code)?;
+        }
+        if let Some(addr) = self.fault_address {
+            writeln!(f, "Accessed address: {:?}", addr)?;
+        }
+        if let Some(id) = self.stack_overflow {
+            writeln!(f, "Kernel stack overflow in proc {}", id)?;
+        }
+        write!(f, "Instruction bytes at {:?}:", self.instruction_pointer)?;
+        match self.instruction_bytes {
+            Some(bytes) => {
+                for b in bytes.iter() {
+                    write!(f, " {:02x}", b)?;
+                }
+                writeln!(f)?;
+            },
+            None => writeln!(f, " <not mapped>")?,
+        }
+        writeln!(f, "InterruptStackFrame {{")?;
+        writeln!(f, "    instruction_pointer: {:?},", self.instruction_pointer)?;
+        writeln!(f, "    code_segment: {:#x},", self.code_segment)?;
+        writeln!(f, "    cpu_flags: {:#x},", self.cpu_flags)?;
+        writeln!(f, "    stack_pointer: {:?},", self.stack_pointer)?;
+        writeln!(f, "    stack_segment: {:#x},", self.stack_segment)?;
+        write!(f, "}}")
+    }
+}
+
+fn instruction_bytes(ip: VirtAddr) -> Option<[u8; INSTRUCTION_BYTES]> {
+    use crate::memory::{translate_addr, phys_mem_offset};
+
+    let offset = phys_mem_offset();
+    if offset.as_u64() == 0 {
+        return None;
+    }
+
+    // the instruction may straddle a page boundary so check both ends
+    let last = ip.as_u64().checked_add(INSTRUCTION_BYTES as u64 - 1)?;
+    unsafe {
+        translate_addr(ip, offset)?;
+        translate_addr(VirtAddr::try_new(last).ok()?, offset)?;
+    }
+
+    let mut bytes = [0; INSTRUCTION_BYTES];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = unsafe { (ip + i).as_ptr::<u8>().read_volatile() };
+    }
+    Some(bytes)
+}
+
+// Returns true if a test asked for this exception, in which case the
+// faulting instruction is skipped.
+fn expected(stack_frame: &mut InterruptStackFrame, report: &ExceptionReport) -> bool {
+    if EXPECTED_VECTOR.load(Ordering::SeqCst) != report.vector as usize {
+        return false;
+    }
+    EXPECTED_VECTOR.store(NO_EXPECTATION, Ordering::SeqCst);
+
+    let skip = SKIP_BYTES.load(Ordering::SeqCst) as u64;
+    unsafe {
+        stack_frame.as_mut().instruction_pointer += skip;
+    }
+    *LAST_REPORT.lock() = Some(*report);
+    true
+}
+
+// Exit code of a proc killed by an exception in user mode
+pub fn fault_status(vector: u8) -> i32 {
+    -128 - vector as i32
+}
+
+// Exceptions in user mode only end the proc that raised them
+fn fatal(stack_frame: &mut InterruptStackFrame, report: ExceptionReport) {
+    if expected(stack_frame, &report) {
+        return;
+    }
+    if report.code_segment & 3 == 3 {
+        kill_current(report);
+    }
+    panic!("{}", report);
+}
+
+// Coming from ring 3 the handler runs on the proc's own kernel stack with
+// nothing locked, so the proc can exit right here
+fn kill_current(report: ExceptionReport) -> ! {
+    crate::dbg_println!("Proc {} killed by {}", crate::task::running_id(), report);
+    x86_64::instructions::interrupts::enable();
+    crate::task::exit(fault_status(report.vector));
+}
+
+// Debug exceptions and NMIs can arrive while the serial port is locked, so
+// the report is dropped rather than waited for
+fn resumable(stack_frame: &mut InterruptStackFrame, report: ExceptionReport) {
+    if expected(stack_frame, &report) {
+        return;
+    }
+    crate::debug::try_print(format_args!("{}\n", report));
+}
+
+macro_rules! exception_handler {
+    ($handler:ident, $vector:expr, $name:expr, $action:ident) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame) {
+            let report = ExceptionReport::new(stack_frame, $vector, $name, None);
+            $action(stack_frame, report);
+        }
+    };
+    ($handler:ident, $vector:expr, $name:expr, $action:ident, $code:path) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame,
+                                           error_code: u64) {
+            let report = ExceptionReport::new(stack_frame, $vector, $name,
+                                              Some($code(error_code)));
+            $action(stack_frame, report);
+        }
+    };
+}
+
+exception_handler!(divide_error_handler, 0, "DIVIDE ERROR", fatal);
+exception_handler!(debug_handler, 1, "DEBUG", resumable);
+exception_handler!(non_maskable_interrupt_handler, 2, "NON MASKABLE INTERRUPT", resumable);
+exception_handler!(breakpoint_handler, 3, "BREAKPOINT", resumable);
+exception_handler!(overflow_handler, 4, "OVERFLOW", fatal);
+exception_handler!(bound_range_exceeded_handler, 5, "BOUND RANGE EXCEEDED", fatal);
+exception_handler!(invalid_opcode_handler, 6, "INVALID OPCODE", fatal);
+exception_handler!(device_not_available_handler, 7, "DEVICE NOT AVAILABLE", fatal);
+exception_handler!(invalid_tss_handler, 10, "INVALID TSS", fatal, ErrorCode::Selector);
+exception_handler!(segment_not_present_handler, 11, "SEGMENT NOT PRESENT", fatal, ErrorCode::Selector);
+exception_handler!(stack_segment_fault_handler, 12, "STACK SEGMENT FAULT", fatal, ErrorCode::Selector);
+exception_handler!(general_protection_fault_handler, 13, "GENERAL PROTECTION FAULT", fatal, ErrorCode::Selector);
+exception_handler!(x87_floating_point_handler, 16, "X87 FLOATING POINT", fatal);
+exception_handler!(alignment_check_handler, 17, "ALIGNMENT CHECK", fatal, ErrorCode::Raw);
+exception_handler!(simd_floating_point_handler, 19, "SIMD FLOATING POINT", fatal);
+exception_handler!(virtualization_handler, 20, "VIRTUALIZATION", fatal);
+exception_handler!(security_exception_handler, 30, "SECURITY EXCEPTION", fatal, ErrorCode::Raw);
+
+// Runs on its own stack, so a kernel stack overflow ends up here once the
+// page fault can't be pushed onto the guard page
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: &mut InterruptStackFrame, error_code: u64) -> ! {
+    use x86_64::registers::control::Cr2;
+    use crate::memory::kernel_stack::guard_page_owner;
+
+    let mut report = ExceptionReport::new(stack_frame, 8, "DOUBLE FAULT",
+                                          Some(ErrorCode::Raw(error_code)));
+    report.stack_overflow = guard_page_owner(Cr2::read())
+        .or_else(|| guard_page_owner(stack_frame.stack_pointer));
+    panic!("{}", report);
+}
+
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: &mut InterruptStackFrame) -> ! {
+    let report = ExceptionReport::new(stack_frame, 18, "MACHINE CHECK", None);
+    panic!("{}", report);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    let addr = Cr2::read();
+
+    // Faults on non-present pages may belong to a demand paged region
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && crate::memory::paging::demand_page(addr) {
+        return;
+    }
+
+    // Writes to copy-on-write pages get a private copy
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && crate::memory::paging::copy_on_write(addr) {
+        return;
+    }
+
+    let mut report = ExceptionReport::new(stack_frame, 14, "PAGE FAULT",
+                                          Some(ErrorCode::PageFault(error_code)));
+    report.fault_address = Some(addr);
+    report.stack_overflow = crate::memory::kernel_stack::guard_page_owner(addr);
+    fatal(stack_frame, report);
+}
+
+#[cfg(test)]
+fn trigger<F: FnOnce()>(vector: u8, skip_bytes: usize, f: F) -> ExceptionReport {
+    expect_exception(vector, skip_bytes);
+    f();
+    take_report().expect("exception was not raised")
+}
+
+// Software interrupts leave the instruction pointer after the `int`, so
+// nothing needs to be skipped.
+#[test_case]
+fn test_software_exceptions() {
+    let report = trigger(1, 0, || unsafe { asm!("int 1" :::: "intel", "volatile") });
+    assert_eq!(report.name, "DEBUG");
+    let report = trigger(2, 0, || unsafe { asm!("int 2" :::: "intel", "volatile") });
+    assert_eq!(report.name, "NON MASKABLE INTERRUPT");
+    let report = trigger(3, 0, || unsafe { asm!("int3" :::: "intel", "volatile") });
+    assert_eq!(report.name, "BREAKPOINT");
+    let report = trigger(4, 0, || unsafe { asm!("int 4" :::: "intel", "volatile") });
+    assert_eq!(report.name, "OVERFLOW");
+    let report = trigger(5, 0, || unsafe { asm!("int 5" :::: "intel", "volatile") });
+    assert_eq!(report.name, "BOUND RANGE EXCEEDED");
+    let report = trigger(7, 0, || unsafe { asm!("int 7" :::: "intel", "volatile") });
+    assert_eq!(report.name, "DEVICE NOT AVAILABLE");
+    assert!(report.error_code.is_none());
+}
+
+#[test_case]
+fn test_divide_error() {
+    // div r64 is encoded in 3 bytes
+    let report = trigger(0, 3, || unsafe {
+        let _quotient: u64;
+        let _remainder: u64;
+        asm!("div rcx" : "={rax}"(_quotient), "={rdx}"(_remainder)
+             : "{rcx}"(0u64), "{rax}"(1u64), "{rdx}"(0u64) : : "intel", "volatile")
+    });
+    assert_eq!(report.vector, 0);
+    assert_eq!(report.name, "DIVIDE ERROR");
+    assert_eq!(report.instruction_bytes.map(|b| [b[0], b[1], b[2]]), Some([0x48, 0xf7, 0xf1]));
+}
+
+#[test_case]
+fn test_invalid_opcode() {
+    let report = trigger(6, 2, || unsafe { asm!("ud2" :::: "intel", "volatile") });
+    assert_eq!(report.name, "INVALID OPCODE");
+    assert_eq!(report.instruction_bytes.map(|b| [b[0], b[1]]), Some([0x0f, 0x0b]));
+}
+
+#[test_case]
+fn test_general_protection_fault() {
+    // a non-canonical address through a DS based operand; mov rax, [rbx]
+    let report = trigger(13, 3, || unsafe {
+        asm!("mov rax, [rbx]" : : "{rbx}"(0x8000_0000_0000_0000u64) : "rax"
+             : "intel", "volatile")
+    });
+    assert_eq!(report.name, "GENERAL PROTECTION FAULT");
+    assert_eq!(report.error_code, Some(ErrorCode::Selector(0)));
+}
+
+#[test_case]
+fn test_stack_segment_fault() {
+    // a non-canonical address through an SS based operand; mov al, [rsp + rcx]
+    let report = trigger(12, 3, || unsafe {
+        asm!("mov al, [rsp + rcx]" : : "{rcx}"(0x8000_0000_0000_0000u64) : "rax"
+             : "intel", "volatile")
+    });
+    assert_eq!(report.name, "STACK SEGMENT FAULT");
+    assert_eq!(report.error_code, Some(ErrorCode::Selector(0)));
+}
+
+#[test_case]
+fn test_page_fault_report() {
+    let report = trigger(14, 3, || unsafe {
+        asm!("mov rax, [rbx]" : : "{rbx}"(0x0dea_d000_0000u64) : "rax"
+             : "intel", "volatile")
+    });
+    assert_eq!(report.name, "PAGE FAULT");
+    assert_eq!(report.fault_address, Some(VirtAddr::new(0x0dea_d000_0000)));
+    assert_eq!(report.error_code, Some(ErrorCode::PageFault(PageFaultErrorCode::empty())));
+}
+
+#[test_case]
+fn test_guard_page_report() {
+    use crate::memory::kernel_stack::KernelStack;
+
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    stack.set_owner(7);
+    let guard = stack.guard_page().start_address() + 4088u64;
+    let report = trigger(14, 3, || unsafe {
+        asm!("mov rax, [rbx]" : : "{rbx}"(guard.as_u64()) : "rax"
+             : "intel", "volatile")
+    });
+    assert_eq!(report.fault_address, Some(guard));
+    assert_eq!(report.stack_overflow, Some(7));
+    assert!(alloc::format!("{}", report).contains("Kernel stack overflow in proc 7"));
+}
+
+#[test_case]
+fn test_user_fault() {
+    // ud2 in ring 3 only ends the proc that ran it
+    assert_eq!(crate::syscall::run_user_code(&[0x0f, 0x0b]), fault_status(6));
+}
+
+// Alignment check needs CPL3, and invalid TSS, segment not present, x87,
+// SIMD, machine check, virtualization and security exceptions can't be
+// raised from kernel code without corrupting the CPU state, so they are
+// only checked for their report formatting.
+#[test_case]
+fn test_error_code_format() {
+    use alloc::format;
+
+    let code = ErrorCode::Selector((5 << 3) | (0b01 << 1) | 1);
+    assert_eq!(format!("{}", code), "0x2b (index 5 in IDT, external event)");
+    assert_eq!(format!("{}", ErrorCode::Raw(0)), "0x0");
+}
@@ -1,32 +1,89 @@
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
 
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
 
 use crate::TSS;
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// The TSS the GDT descriptor points at. The CPU reads it behind our back,
+// so it's only ever written through the cell, never through a shared
+// reference.
+pub struct TaskState(UnsafeCell<TaskStateSegment>);
+
+// There is one CPU, and set_kernel_stack runs with the task manager locked
+unsafe impl Sync for TaskState {}
+
+impl TaskState {
+    pub fn new(tss: TaskStateSegment) -> TaskState {
+        TaskState(UnsafeCell::new(tss))
+    }
+}
+
+// Present, user segment (code/data), writable
+const KERNEL_DATA_SEGMENT: u64 = (1 << 47) | (1 << 44) | (1 << 41);
+
 lazy_static! {
+    // The order of the segments matters for SYSCALL/SYSRET: kernel data
+    // must follow kernel code, and user code must follow user data.
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector =  gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
+        (gdt, Selectors { 
+            code_selector, 
+            data_selector, 
+            user_data_selector, 
+            user_code_selector, 
+            tss_selector 
+        })
     };
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{set_cs, load_ss, load_ds};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
+        load_ss(GDT.1.data_selector);
+        load_ds(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+// Sets the stack the CPU switches to when an interrupt arrives while
+// running in ring 3. Updated on every context switch.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = stack_top;
+    }
+    crate::syscall::set_kernel_stack(stack_top);
+}
+
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] }
+}
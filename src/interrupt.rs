@@ -5,18 +5,21 @@ use spin::Mutex;
 
 use pic8259_simple::ChainedPics;
 
-use crate::println;
-use crate::gdt;
+use crate::exception;
+use crate::wait::InputQueue;
+
+lazy_static! {
+    // Decoded key presses as UTF-8
+    pub static ref KEYBOARD_INPUT: InputQueue = InputQueue::new();
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        exception::set_exception_handlers(&mut idt);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
         idt
     };
 }
@@ -30,7 +33,9 @@ pub static PICS: Mutex<ChainedPics> = Mutex::new( unsafe { ChainedPics::new(PIC_
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard
+    Keyboard,
+    // COM1 is IRQ 4
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -47,21 +52,14 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT \n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: &mut InterruptStackFrame){
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: &mut InterruptStackFrame) {
+    crate::pit::tick();
     unsafe  {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
     }
+    // May not return until this task is scheduled again
+    crate::scheduler::timer_tick();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
@@ -81,7 +79,13 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => crate::print!("{}", character),
+                DecodedKey::Unicode(character) => {
+                    crate::print!("{}", character);
+                    let mut bytes = [0; 4];
+                    for &b in character.encode_utf8(&mut bytes).as_bytes() {
+                        KEYBOARD_INPUT.push(b);
+                    }
+                },
                 DecodedKey::RawKey(key) => crate::print!("{:?}", key),
             }
         }
@@ -93,3 +97,13 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 
 }
+
+extern "x86-interrupt" fn serial_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+    crate::debug::receive_serial();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+}
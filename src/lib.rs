@@ -5,6 +5,7 @@
 #![feature(const_fn)]
 #![feature(alloc_error_handler)]
 #![feature(asm)]
+#![feature(global_asm)]
 
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
@@ -14,10 +15,17 @@
 extern crate alloc;
 
 pub mod interrupt;
+pub mod exception;
 pub mod vga_text_buffer;
 pub mod gdt;
 pub mod memory;
 pub mod task;
+pub mod scheduler;
+pub mod pit;
+pub mod wait;
+pub mod usermode;
+pub mod syscall;
+pub mod elf;
 pub mod rtc;
 pub mod debug;
 
@@ -37,24 +45,30 @@ entry_point!(test_kernel_main);
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    // Only reached through gdt, which updates the privilege stack on
+    // context switches
+    static ref TSS: gdt::TaskState = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
+            // enough to format and print a report, e.g. of a kernel
+            // stack overflow
+            const STACK_SIZE: usize = 5 * 4096;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe {&STACK});
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss
+        gdt::TaskState::new(tss)
     };
 }
 
 pub fn init() {
     gdt::init();
     interrupt::init_idt();
+    syscall::init();
     unsafe { interrupt::PICS.lock().initialize() };
+    pit::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -91,6 +105,8 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
         dbg_print!(".");
     }
     dbg_println!("\n\x1b[32m[ok]\x1b[0m");
+    #[cfg(feature = "alloc_tracking")]
+    memory::allocator::report_leaks();
     exit();
 }
 
@@ -103,12 +119,20 @@ pub fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(_boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::paging::offset_page_table(phys_mem_offset) };
 
+    memory::set_phys_mem_offset(phys_mem_offset);
+    memory::meminfo::set_memory_map(&_boot_info.memory_map);
+
     let mut frame_allocator = unsafe { 
-        memory::BootInfoFrameAllocator::init(&_boot_info.memory_map)
+        memory::BuddyFrameAllocator::init(&_boot_info.memory_map, phys_mem_offset)
     };
+    memory::address_space::init(&mut frame_allocator);
+    let mut memory_manager = memory::paging::MemoryManager::new(frame_allocator);
 
-    memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
+    let heap_region = memory::allocator::init_heap(&mut mapper, &mut memory_manager.frame_allocator)
         .expect("Heap initialization failed");
+    memory_manager.heap_was_init_at(heap_region);
+    *memory::paging::MEMORY_MANAGER.lock() = Some(memory_manager);
+    scheduler::init();
     // using a global variable for testing purposes only
     unsafe {
         PHYS_OFFSET = _boot_info.physical_memory_offset; 
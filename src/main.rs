@@ -10,7 +10,7 @@ extern crate alloc;
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
 
-use oslib::{init, println, dbg_println, halt_loop, task::TaskManager};
+use oslib::{init, println, dbg_println, halt_loop};
 
 #[cfg(not(test))]
 entry_point!(kernel_main);
@@ -28,34 +28,45 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    memory::set_phys_mem_offset(phys_mem_offset);
+    memory::meminfo::set_memory_map(&boot_info.memory_map);
 
-    let frame_allocator = unsafe { 
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mut frame_allocator = unsafe { 
+        memory::BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
+    memory::address_space::init(&mut frame_allocator);
     let mut mapper = unsafe { memory::paging::offset_page_table(phys_mem_offset) };
     let mut memory_manager = memory::paging::MemoryManager::new(frame_allocator);
 
     let heap_region = memory::allocator::init_heap(&mut mapper, &mut memory_manager.frame_allocator)
         .expect("Heap initialization failed");
     memory_manager.heap_was_init_at(heap_region);
+    dbg_println!("Physical frames: {} used / {} total", 
+                 memory_manager.frame_allocator.used_frames(),
+                 memory_manager.frame_allocator.total_frames());
     let test_addr = VirtAddr::new(0x0f00000000);
     use x86_64::structures::paging::mapper::MapperAllSizes;
-    memory_manager.request_address_space_at(test_addr, 5 * 1024, &mut mapper);
+    memory_manager.request_address_space_at(test_addr, 5 * 1024, &mut mapper)
+        .expect("could not request address space");
     dbg_println!("Requested address space from memory manager. Page should be mapped");
     dbg_println!("{:?} -> {:?}", test_addr, mapper.translate(test_addr));
     dbg_println!("Memory regions: {:?}", memory_manager.get_used_regions());
     dbg_println!("");
     dbg_println!("Relinquishing address space");
-    memory_manager.relinquish_address_space(test_addr, 5 * 1024, &mut mapper);
+    memory_manager.relinquish_address_space(test_addr, 5 * 1024, &mut mapper)
+        .expect("could not relinquish address space");
     dbg_println!("Memory regions: {:?}", memory_manager.get_used_regions());
     dbg_println!("");
-    memory_manager.request_address_space_at(test_addr, 1024, &mut mapper);
+    memory_manager.request_address_space_at(test_addr, 1024, &mut mapper)
+        .expect("could not request address space");
     dbg_println!("{:?} -> {:?}", test_addr, mapper.translate(test_addr));
     dbg_println!("Memory regions: {:?}", memory_manager.get_used_regions());
 
+    *memory::paging::MEMORY_MANAGER.lock() = Some(memory_manager);
+    dbg_println!("{}", memory::meminfo::MemInfo::collect());
 
     dbg_println!("Initializing task manager");
-    TaskManager::new();
+    oslib::scheduler::init();
     dbg_println!("TaskManager initialized");
 
     dbg_println!("Boot time: {}", *oslib::rtc::BOOT_TIME);
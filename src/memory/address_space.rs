@@ -0,0 +1,370 @@
+use x86_64::{
+    structures::paging::{PageTable, PhysFrame, OffsetPageTable, UnusedPhysFrame,
+        Size4KiB, FrameAllocator, FrameDeallocator, page_table::PageTableFlags},
+    PhysAddr,
+};
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::phys_mem_offset;
+
+// Marks a read only page that gets a private copy when written to
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// Physical address of the page table the kernel booted with. Every address
+// space shares its mappings.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+// One bit per level 4 entry of the kernel half, fixed by init
+static KERNEL_ENTRIES: [AtomicU64; 8] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+
+// Kernel memory mapped after init that doesn't share a level 4 entry with
+// the kernel image. Their entries are created up front so that they are
+// part of the kernel half from the start.
+const KERNEL_AREAS: [u64; 1] = [super::allocator::HEAP_START as u64];
+
+// Records the active level 4 table as the kernel's and fixes the kernel
+// half. Must be called before any other address space is created.
+pub fn init<A: FrameAllocator<Size4KiB>>(frame_allocator: &mut A) {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+
+    let (frame, _) = Cr3::read();
+    KERNEL_PAGE_TABLE.store(frame.start_address().as_u64(), Ordering::SeqCst);
+
+    let kernel = unsafe { table_at(frame) };
+    for &area in KERNEL_AREAS.iter() {
+        let entry = &mut kernel[usize::from(x86_64::VirtAddr::new(area).p4_index())];
+        if entry.is_unused() {
+            let table = *frame_allocator.allocate_frame().expect("no frame for a kernel page table");
+            unsafe { table_at(table).zero() };
+            entry.set_frame(table, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+    for (index, entry) in kernel.iter().enumerate() {
+        if !entry.is_unused() {
+            KERNEL_ENTRIES[index / 64].fetch_or(1 << (index % 64), Ordering::SeqCst);
+        }
+    }
+
+    // Kernel writes to copy-on-write pages have to fault as well
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    super::paging::enable_no_execute();
+    super::pat::init();
+}
+
+pub fn kernel_page_table() -> PhysFrame {
+    let addr = KERNEL_PAGE_TABLE.load(Ordering::SeqCst);
+    assert!(addr != 0, "address spaces are not initialized");
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+// A mapper for the kernel's own page table, whichever one is active
+pub unsafe fn kernel_mapper() -> OffsetPageTable<'static> {
+    OffsetPageTable::new(table_at(kernel_page_table()), phys_mem_offset())
+}
+
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = phys_mem_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+// The kernel half is every level 4 entry the kernel's page table used
+// after init. The bootloader doesn't keep the kernel in the upper half so
+// these are scattered across the address space.
+pub fn is_kernel_entry(index: usize) -> bool {
+    KERNEL_ENTRIES[index / 64].load(Ordering::SeqCst) & (1 << (index % 64)) != 0
+}
+
+// True if addr lies in the part of a process address space that isn't
+// shared with the kernel. Mapping user memory anywhere else would change
+// the kernel's tables and with them every other process.
+pub fn is_user_address(addr: x86_64::VirtAddr) -> bool {
+    !is_kernel_entry(usize::from(addr.p4_index()))
+}
+
+// True if every page of addr..addr + size is in the kernel half. Kernel
+// memory mapped anywhere else wouldn't be seen by other address spaces.
+pub fn is_kernel_range(addr: x86_64::VirtAddr, size: usize) -> bool {
+    let last = match addr.as_u64().checked_add(size.max(1) as u64 - 1)
+        .and_then(|last| x86_64::VirtAddr::try_new(last).ok()) {
+        Some(last) => last,
+        None => return false,
+    };
+    let (first, last) = (usize::from(addr.p4_index()), usize::from(last.p4_index()));
+    (first..=last).all(is_kernel_entry)
+}
+
+// True if addr..addr + size lies in the lower half and shares no level 4
+// entry with the kernel
+pub fn is_user_range(addr: x86_64::VirtAddr, size: usize) -> bool {
+    let last = match addr.as_u64().checked_add(size.max(1) as u64 - 1) {
+        Some(last) if last < 0x0000_8000_0000_0000 => x86_64::VirtAddr::new(last),
+        _ => return false,
+    };
+    let (first, last) = (usize::from(addr.p4_index()), usize::from(last.p4_index()));
+    (first..=last).all(|index| !is_kernel_entry(index))
+}
+
+// Number of address spaces mapping each shared frame. Frames that aren't
+// in here have a single owner.
+#[derive(Debug, Default)]
+pub struct FrameRefs {
+    counts: BTreeMap<u64, usize>,
+}
+
+impl FrameRefs {
+    pub fn new() -> FrameRefs {
+        FrameRefs { counts: BTreeMap::new() }
+    }
+
+    // Records one more owner of frame
+    pub fn share(&mut self, frame: PhysFrame) {
+        *self.counts.entry(frame.start_address().as_u64()).or_insert(1) += 1;
+    }
+
+    pub fn is_shared(&self, frame: PhysFrame) -> bool {
+        self.counts.contains_key(&frame.start_address().as_u64())
+    }
+
+    pub fn count(&self, frame: PhysFrame) -> usize {
+        self.counts.get(&frame.start_address().as_u64()).cloned().unwrap_or(1)
+    }
+
+    // Drops one owner of frame. Returns true if that was the last one and
+    // the frame can be freed.
+    pub fn release(&mut self, frame: PhysFrame) -> bool {
+        let key = frame.start_address().as_u64();
+        match self.counts.get_mut(&key) {
+            Some(count) if *count > 2 => {
+                *count -= 1;
+                false
+            },
+            Some(_) => {
+                self.counts.remove(&key);
+                false
+            },
+            None => true,
+        }
+    }
+}
+
+// A level 4 table owned by a single process
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    // Creates a page table with the kernel half shared and an empty user
+    // half. The kernel only maps memory in the kernel half, so whatever it
+    // maps later is visible here as well.
+    pub fn new<A: FrameAllocator<Size4KiB>>(frame_allocator: &mut A) -> Option<AddressSpace> {
+        let frame = frame_allocator.allocate_frame()?;
+        let level_4_frame = *frame;
+
+        unsafe {
+            let kernel = table_at(kernel_page_table());
+            let table = table_at(level_4_frame);
+            table.zero();
+            for index in 0..512 {
+                if is_kernel_entry(index) {
+                    table[index] = kernel[index].clone();
+                }
+            }
+        }
+
+        Some(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    // A mapper for this address space. It is valid whether or not the
+    // address space is active, but the caller must not create aliasing
+    // mappers for the same table.
+    pub unsafe fn page_table(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table_at(self.level_4_frame), phys_mem_offset())
+    }
+
+    // Creates a copy of the user half that shares every page. Writable
+    // pages become read only and copy-on-write in both address spaces.
+    pub fn fork<A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>>(
+        &self, frame_allocator: &mut A, refs: &mut FrameRefs) -> Option<AddressSpace> {
+        use x86_64::registers::control::Cr3;
+
+        let child = AddressSpace::new(frame_allocator)?;
+        let mut result = Some(());
+        unsafe {
+            let parent_table = table_at(self.level_4_frame);
+            let child_table = table_at(child.level_4_frame);
+            for index in 0..512 {
+                if parent_table[index].is_unused() || is_kernel_entry(index) {
+                    continue;
+                }
+                let frame = parent_table[index].frame().expect("huge level 4 entry");
+                match copy_table(frame, 3, frame_allocator, refs) {
+                    Some(copy) => child_table[index].set_frame(copy, parent_table[index].flags()),
+                    None => {
+                        result = None;
+                        break;
+                    },
+                }
+            }
+        }
+
+        // parent mappings lost WRITABLE
+        if Cr3::read().0 == self.level_4_frame {
+            x86_64::instructions::tlb::flush_all();
+        }
+
+        match result {
+            Some(()) => Some(child),
+            None => {
+                child.destroy(frame_allocator, refs);
+                None
+            },
+        }
+    }
+
+    // Frees every user page, the user page tables and the level 4 table.
+    // Pages still mapped by another address space are left alone. The
+    // address space must not be active.
+    pub fn destroy<A: FrameDeallocator<Size4KiB>>(self, frame_allocator: &mut A, refs: &mut FrameRefs) {
+        use x86_64::registers::control::Cr3;
+
+        assert!(Cr3::read().0 != self.level_4_frame, "destroying the active address space");
+
+        unsafe {
+            let table = table_at(self.level_4_frame);
+            for index in 0..512 {
+                if table[index].is_unused() || is_kernel_entry(index) {
+                    continue;
+                }
+                free_table(table[index].frame().expect("huge level 4 entry"), 3, frame_allocator, refs);
+                table[index].set_unused();
+            }
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(self.level_4_frame));
+        }
+    }
+}
+
+// Frees the table at frame and everything mapped below it. Level 1 entries
+// are pages, level 2 and 3 entries may also be huge pages.
+unsafe fn free_table<A: FrameDeallocator<Size4KiB>>(frame: PhysFrame, level: usize,
+                                                    frame_allocator: &mut A, refs: &mut FrameRefs) {
+    let table = table_at(frame);
+    for entry in table.iter_mut() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 1 {
+            let page_frame = PhysFrame::containing_address(entry.addr());
+            if refs.release(page_frame) {
+                frame_allocator.deallocate_frame(UnusedPhysFrame::new(page_frame));
+            }
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // FIXME: huge pages are never mapped into user space, and the
+            // deallocator can only free 4KiB frames
+        } else {
+            free_table(PhysFrame::containing_address(entry.addr()), level - 1, frame_allocator, refs);
+        }
+        entry.set_unused();
+    }
+    frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+}
+
+// Copies the table at frame and the tables below it, sharing the pages
+// they map. Returns the new table.
+unsafe fn copy_table<A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>>(
+    frame: PhysFrame, level: usize, frame_allocator: &mut A, refs: &mut FrameRefs) -> Option<PhysFrame> {
+    let copy = *frame_allocator.allocate_frame()?;
+    let table = table_at(frame);
+    let copy_table_ref = table_at(copy);
+    copy_table_ref.zero();
+
+    for (index, entry) in table.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 1 {
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                entry.set_flags(flags);
+            }
+            let page_frame = PhysFrame::containing_address(entry.addr());
+            refs.share(page_frame);
+            copy_table_ref[index].set_addr(entry.addr(), flags);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // FIXME: huge pages are never mapped into user space
+        } else {
+            let next = PhysFrame::containing_address(entry.addr());
+            match copy_table(next, level - 1, frame_allocator, refs) {
+                Some(next_copy) => copy_table_ref[index].set_addr(next_copy.start_address(), entry.flags()),
+                None => {
+                    // leave a consistent table behind to be destroyed
+                    free_table(copy, level, frame_allocator, refs);
+                    return None;
+                },
+            }
+        }
+    }
+    Some(copy)
+}
+
+#[test_case]
+fn test_address_space() {
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::mapper::MapperAllSizes;
+    use super::paging::MEMORY_MANAGER;
+
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+    let used = manager.frame_allocator.used_frames();
+
+    let space = AddressSpace::new(&mut manager.frame_allocator)
+        .expect("could not create address space");
+    let mut mapper = unsafe { space.page_table() };
+
+    let addr = (0..256u64)
+        .map(|index| VirtAddr::new(index << 39))
+        .find(|&addr| is_user_address(addr))
+        .expect("no free level 4 entry");
+    manager.request_user_address_space_at(addr, 3 * 4096, &mut mapper)
+        .expect("could not map into address space");
+
+    // the mapping is only visible through the new table
+    assert!(mapper.translate_addr(addr).is_some());
+    let kernel = unsafe { super::paging::active_page_table() };
+    assert!(kernel.translate_addr(addr).is_none());
+
+    // kernel mappings are shared
+    let heap = VirtAddr::new(super::allocator::HEAP_START as u64);
+    assert_eq!(mapper.translate_addr(heap), kernel.translate_addr(heap));
+
+    // forked pages are shared until written to
+    let child = manager.fork_address_space(&space).expect("could not fork address space");
+    let mut child_mapper = unsafe { child.page_table() };
+    let frame = mapper.translate_addr(addr);
+    assert_eq!(child_mapper.translate_addr(addr), frame);
+    assert!(manager.frame_refs.is_shared(PhysFrame::containing_address(frame.unwrap())));
+
+    manager.handle_write_fault(addr, &mut child_mapper);
+    assert!(child_mapper.translate_addr(addr) != frame);
+    assert!(!manager.frame_refs.is_shared(PhysFrame::containing_address(frame.unwrap())));
+
+    drop(child_mapper);
+    manager.destroy_address_space(child);
+    drop(mapper);
+    manager.destroy_address_space(space);
+    assert!(manager.get_used_regions().iter().all(|region| !region.contains(addr)));
+    assert_eq!(manager.frame_allocator.used_frames(), used);
+}
@@ -2,28 +2,112 @@ use alloc::alloc::{GlobalAlloc, Layout, AllocErr};
 use core::ptr::NonNull;
 
 use super::list_allocator::{ListHeap, HoleInfo};
+use super::slab_allocator::SlabHeap;
+use super::tracking::{TrackedHeap, TrackingScope, SizeClassStats, SIZE_CLASSES};
 
 use lazy_static::lazy_static;
 
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PhysFrame,
+        Size4KiB, UnusedPhysFrame,
     },
+    registers::control::Cr3,
+    PhysAddr,
     VirtAddr,
 };
 
-use super::paging::MemoryRegion;
-
-// TODO: implement Slab allocator and possibly abstract out a heap interface
+use super::paging::{MemoryRegion, Protection};
 
 #[global_allocator]
-pub static ALLOCATOR: LockedListHeap = LockedListHeap::empty();
+pub static ALLOCATOR: TrackedHeap<LockedHeap<SlabHeap>> =
+    TrackedHeap::new(LockedHeap::empty_from_heap(SlabHeap::empty()));
 
 // TODO: Find appropriate values for these
 pub const HEAP_START: usize = 0x4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
+// The heap grows on exhaustion until it reaches this size
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+// Smallest amount the heap grows by at once
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+// Frames kept for the heap, enough to grow by HEAP_GROW_SIZE
+const RESERVE_FRAMES: usize = 32;
+
+// Frames the heap grows with and takes slab pages from while the memory
+// manager is busy, which it is whenever it allocates itself. Topped up each
+// time the heap gets hold of the manager.
+struct FrameReserve {
+    frames: [u64; RESERVE_FRAMES],
+    count: usize,
+    // Bytes mapped at the heap top from the reserve that the memory
+    // manager doesn't know of yet
+    unrecorded: usize,
+}
+
+// Only locked with the heap locked
+static FRAME_RESERVE: Mutex<FrameReserve> = Mutex::new(FrameReserve::new());
+
+impl FrameReserve {
+    const fn new() -> FrameReserve {
+        FrameReserve {
+            frames: [0; RESERVE_FRAMES],
+            count: 0,
+            unrecorded: 0,
+        }
+    }
+
+    fn refill(&mut self, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+        while self.count < RESERVE_FRAMES {
+            match frame_allocator.allocate_frame() {
+                Some(frame) => {
+                    self.frames[self.count] = frame.start_address().as_u64();
+                    self.count += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    // Maps size bytes at addr with frames of the reserve. Fails without
+    // mapping anything unless there are enough frames for the worst case of
+    // a level 2 and a level 1 table per 2MiB.
+    fn map_at(&mut self, addr: VirtAddr, size: usize, mapper: &mut OffsetPageTable) -> bool {
+        let pages = size / Size4KiB::SIZE as usize;
+        let tables = 2 * (pages / 512 + 1);
+        if self.count < pages + tables {
+            return false;
+        }
+
+        let flags = (Protection::READ | Protection::WRITE).page_table_flags();
+        let first = Page::containing_address(addr);
+        for page in Page::range(first, first + pages as u64) {
+            let frame = self.allocate_frame().expect("heap frame reserve ran out");
+            mapper.map_to(page, frame, flags, &mut *self)
+                .expect("could not map heap page")
+                .flush();
+        }
+        self.unrecorded += size;
+        true
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FrameReserve {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.frames[self.count]));
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+// A frame for a slab page while the memory manager is busy
+pub(super) fn reserve_frame() -> Option<UnusedPhysFrame> {
+    FRAME_RESERVE.lock().allocate_frame()
+}
 
 pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>,
                  frame_allocator: &mut impl FrameAllocator<Size4KiB>
@@ -36,24 +120,116 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>,
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    let protection = Protection::READ | Protection::WRITE;
+    let flags = protection.page_table_flags();
     for page in page_range {
         let frame = frame_allocator.allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         mapper.map_to(page, frame, flags, frame_allocator)?.flush();
     }
 
+    FRAME_RESERVE.lock().refill(frame_allocator);
+
     unsafe {
-        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
+        let mut heap = ALLOCATOR.inner().lock();
+        heap.init(HEAP_START, HEAP_SIZE);
+        heap.set_max_size(HEAP_MAX_SIZE);
+        #[cfg(feature = "heap_debug")]
+        heap.list_heap().enable_checks();
     }
+    #[cfg(feature = "alloc_tracking")]
+    ALLOCATOR.track_forever();
 
+    let (page_table, _) = Cr3::read();
     Ok(MemoryRegion {
         start: VirtAddr::new(HEAP_START as u64),
         size: HEAP_SIZE,
+        demand_paged: false,
+        page_table: page_table.start_address(),
+        protection,
     })
 
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub max_size: usize,
+    // Number of times the heap was extended
+    pub grow_count: usize,
+    // Allocations that failed because the heap couldn't be extended
+    pub failed_grows: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.inner().lock().stats()
+}
+
+// Limits how far the kernel heap can grow. It never shrinks below its
+// current size.
+pub fn set_max_heap_size(max_size: usize) {
+    ALLOCATOR.inner().lock().set_max_size(max_size);
+}
+
+// Records kernel allocations until the scope is dropped, see
+// TrackingScope::leaked
+pub fn track_allocations() -> TrackingScope<'static, LockedHeap<SlabHeap>> {
+    ALLOCATOR.track()
+}
+
+// Tags kernel allocations made while tracking, returns the previous tag
+pub fn set_allocation_tag(tag: &'static str) -> &'static str {
+    ALLOCATOR.set_tag(tag)
+}
+
+pub fn allocation_histogram() -> [SizeClassStats; SIZE_CLASSES] {
+    ALLOCATOR.histogram()
+}
+
+pub fn report_leaks() {
+    ALLOCATOR.report_leaks();
+}
+
+// Maps more pages after the top of the heap and hands them to it. The
+// memory manager may be the one allocating or held by a proc that can't
+// run right now, so it isn't waited for: without it the pages come from
+// the frame reserve. The manager is told about them the next time.
+pub(super) fn grow_heap(heap: &mut ListHeap, layout: Layout) -> bool {
+    use super::paging::MEMORY_MANAGER;
+    use super::address_space::kernel_mapper;
+
+    let by = match heap.growth_for(layout, Size4KiB::SIZE as usize) {
+        Some(by) => by,
+        None => return false,
+    };
+
+    // the heap is part of the kernel half shared by every address space.
+    // Its tables are only changed here, under the heap lock.
+    let mut mapper = unsafe { kernel_mapper() };
+    let top = VirtAddr::new(heap.top() as u64);
+    let mut reserve = FRAME_RESERVE.lock();
+    let mut guard = MEMORY_MANAGER.try_lock();
+    let grown = match guard.as_mut().and_then(|guard| guard.as_mut()) {
+        Some(manager) => {
+            if reserve.unrecorded > 0 {
+                let recorded = top - reserve.unrecorded as u64;
+                manager.extend_region(recorded, reserve.unrecorded, &mut mapper)
+                    .expect("heap region is missing");
+                reserve.unrecorded = 0;
+            }
+            let grown = manager.grow_region(top, by, &mut mapper).is_ok();
+            reserve.refill(&mut manager.frame_allocator);
+            grown
+        },
+        None => reserve.map_at(top, by, &mut mapper),
+    };
+    if !grown {
+        return false;
+    }
+    unsafe { heap.extend(by) };
+    true
+}
+
 pub trait Heap {
     // General trait for allocators 
     // TODO: once traits support const fn empty() -> Self where Self: Heap;
@@ -85,31 +261,13 @@ impl <T: Heap> LockedHeap<T> {
     pub unsafe fn new(heap_bottom: usize, heap_size: usize) -> LockedHeap<T> {
         LockedHeap(Mutex::new( T::new(heap_bottom, heap_size)))
     }
-}
-
-unsafe impl <T: Heap> GlobalAlloc for LockedHeap<T> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0.lock().allocate(layout).ok().map_or(0 as *mut u8, |allocation| allocation.as_ptr())
-    }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.0.lock()
     }
 }
 
-pub struct LockedListHeap(Mutex<ListHeap>);
-
-impl LockedListHeap {
-    const fn empty() -> LockedListHeap {
-        LockedListHeap(Mutex::new(ListHeap::empty()))
-    }
-
-    pub unsafe fn new(heap_bottom: usize, heap_size: usize) -> LockedListHeap{
-        LockedListHeap(Mutex::new(ListHeap::new(heap_bottom, heap_size)))
-    }
-}
-
-unsafe impl GlobalAlloc for LockedListHeap {
+unsafe impl <T: Heap> GlobalAlloc for LockedHeap<T> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.0.lock().allocate(layout).ok().map_or(0 as *mut u8, |allocation| allocation.as_ptr())
     }
@@ -119,7 +277,6 @@ unsafe impl GlobalAlloc for LockedListHeap {
     }
 }
 
-
 fn align_down(addr: usize, align: usize) -> usize {
     if align.is_power_of_two() {
         addr & !(align - 1)
@@ -171,3 +328,84 @@ fn test_box() {
 
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn test_heap_growth() {
+    use alloc::vec::Vec;
+
+    let before = heap_stats();
+    // more than the initial heap holds in one piece
+    let big: Vec<u8> = Vec::with_capacity(2 * HEAP_SIZE);
+    let after = heap_stats();
+    assert!(after.grow_count > before.grow_count);
+    assert!(after.size >= before.size + 2 * HEAP_SIZE);
+    assert!(after.size <= after.max_size);
+    drop(big);
+
+    // the grown part is reused rather than grown again
+    let again: Vec<u8> = Vec::with_capacity(2 * HEAP_SIZE);
+    assert_eq!(heap_stats().grow_count, after.grow_count);
+    drop(again);
+
+    // growing past the maximum is refused
+    let layout = Layout::from_size_align(HEAP_MAX_SIZE, 8).unwrap();
+    assert!(ALLOCATOR.inner().lock().list_heap().growth_for(layout, 4096).is_none());
+}
+
+#[test_case]
+fn test_heap_growth_with_memory_manager_locked() {
+    use alloc::vec::Vec;
+    use super::paging::MEMORY_MANAGER;
+
+    let guard = MEMORY_MANAGER.lock();
+    let before = heap_stats();
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(256);
+    while heap_stats().grow_count == before.grow_count {
+        assert!(blocks.len() < blocks.capacity(), "heap never grew");
+        blocks.push(Vec::with_capacity(16 * 1024));
+    }
+    let after = heap_stats();
+    assert!(after.size > before.size);
+    assert_eq!(after.failed_grows, before.failed_grows);
+    drop(blocks);
+    drop(guard);
+
+    // the memory manager learns about the pages when the heap next grows
+    let big: Vec<u8> = Vec::with_capacity(after.size);
+    let mut mapper = unsafe { super::address_space::kernel_mapper() };
+    let guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_ref().expect("memory manager not installed");
+    let heap = manager.find_region(VirtAddr::new(HEAP_START as u64), &mut mapper)
+        .expect("heap region is missing");
+    assert_eq!(heap.size, heap_stats().size);
+    drop(big);
+}
+
+#[test_case]
+fn test_allocation_tracking() {
+    use alloc::{boxed::Box, vec::Vec};
+    use x86_64::instructions::interrupts;
+
+    // other procs allocating in between would show up in the counts
+    interrupts::without_interrupts(|| {
+        let scope = track_allocations();
+        let histogram = allocation_histogram();
+        let previous = set_allocation_tag("test_allocation_tracking");
+
+        let freed: Vec<u64> = Vec::with_capacity(100);
+        let kept = Box::new([0u8; 24]);
+        drop(freed);
+        assert_eq!(scope.leaked(), 1);
+        assert_eq!(scope.leaked_bytes(), 24);
+
+        // 24 bytes fall in the up to 32 class, 800 in the up to 1KiB one
+        let after = allocation_histogram();
+        assert_eq!(after[2].live, histogram[2].live + 1);
+        assert_eq!(after[7].allocations, histogram[7].allocations + 1);
+        assert_eq!(after[7].live, histogram[7].live);
+
+        drop(kept);
+        assert_eq!(scope.leaked(), 0);
+        set_allocation_tag(previous);
+    });
+}
@@ -0,0 +1,218 @@
+use x86_64::{
+    structures::paging::{PhysFrame, UnusedPhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator},
+    VirtAddr,
+    PhysAddr,
+};
+
+use bootloader::bootinfo::{MemoryRegionType, MemoryMap};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: u64 = 64;
+
+// Tracks every physical frame with a single bit. A set bit means the frame
+// is in use (or was never usable to begin with).
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // frame number represented by bit 0
+    base_frame: u64,
+    frame_count: u64,
+    total: usize,
+    used: usize,
+    // index to resume searching from
+    next: u64,
+}
+
+impl BitmapFrameAllocator {
+    // Creates an allocator where every frame in
+    // base..base + frame_count is free.
+    pub fn new(base: PhysFrame, frame_count: u64, bitmap: &'static mut [u64]) -> Self {
+        assert!(bitmap.len() as u64 * BITS_PER_WORD >= frame_count,
+                "bitmap too small for frame count");
+        for word in bitmap.iter_mut() {
+            *word = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            base_frame: base.start_address().as_u64() / FRAME_SIZE,
+            frame_count,
+            total: frame_count as usize,
+            used: 0,
+            next: 0,
+        };
+        // bits past the end of the range are never handed out
+        for index in frame_count..(allocator.bitmap.len() as u64 * BITS_PER_WORD) {
+            allocator.set(index);
+        }
+        allocator
+    }
+
+    // Builds the bitmap from the bootloader memory map. The bitmap itself
+    // is stored in the first usable region large enough to hold it.
+    pub unsafe fn init(memory_map: &'static MemoryMap, phys_mem_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+
+        let words = ((frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize;
+        let bitmap_bytes = (words * core::mem::size_of::<u64>()) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let storage = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_start = storage.range.start_addr();
+        let bitmap_ptr = (phys_mem_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            base_frame: 0,
+            frame_count,
+            total: 0,
+            used: 0,
+            next: 0,
+        };
+
+        for region in usable_regions() {
+            for index in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.clear(index);
+                allocator.total += 1;
+            }
+        }
+
+        let bitmap_first = bitmap_start / FRAME_SIZE;
+        for index in bitmap_first..(bitmap_first + bitmap_frames) {
+            allocator.set(index);
+            allocator.used += 1;
+        }
+
+        allocator
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.used
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.total - self.used
+    }
+
+    pub fn is_used(&self, frame: PhysFrame) -> bool {
+        match self.index_of(frame) {
+            Some(index) => self.test(index),
+            None => true,
+        }
+    }
+
+    fn index_of(&self, frame: PhysFrame) -> Option<u64> {
+        let number = frame.start_address().as_u64() / FRAME_SIZE;
+        if number < self.base_frame || number - self.base_frame >= self.frame_count {
+            return None;
+        }
+        Some(number - self.base_frame)
+    }
+
+    fn frame_at(&self, index: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new((self.base_frame + index) * FRAME_SIZE))
+    }
+
+    fn test(&self, index: u64) -> bool {
+        self.bitmap[(index / BITS_PER_WORD) as usize] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, index: u64) {
+        self.bitmap[(index / BITS_PER_WORD) as usize] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, index: u64) {
+        self.bitmap[(index / BITS_PER_WORD) as usize] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn find_free(&self) -> Option<u64> {
+        let words = self.bitmap.len() as u64;
+        if words == 0 {
+            return None;
+        }
+        let first_word = (self.next / BITS_PER_WORD) % words;
+
+        // start at the hint and wrap around once
+        for i in 0..words {
+            let word_index = (first_word + i) % words;
+            let word = self.bitmap[word_index as usize];
+            if word != !0 {
+                let bit = (!word).trailing_zeros() as u64;
+                let index = word_index * BITS_PER_WORD + bit;
+                if index < self.frame_count {
+                    return Some(index);
+                }
+            }
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        let index = self.find_free()?;
+        self.set(index);
+        self.used += 1;
+        self.next = index + 1;
+        Some(unsafe { UnusedPhysFrame::new(self.frame_at(index)) })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        let index = self.index_of(*frame)
+            .expect("deallocated frame is not managed by this allocator");
+        assert!(self.test(index), "double free of frame {:?}", *frame);
+        self.clear(index);
+        self.used -= 1;
+        if index < self.next {
+            self.next = index;
+        }
+    }
+}
+
+#[test_case]
+fn test_bitmap_allocator() {
+    static mut BITMAP: [u64; 2] = [0; 2];
+
+    let base = PhysFrame::containing_address(PhysAddr::new(0x1000_0000));
+    let mut allocator = BitmapFrameAllocator::new(base, 100, unsafe { &mut BITMAP });
+    assert_eq!(allocator.total_frames(), 100);
+    assert_eq!(allocator.free_frames(), 100);
+
+    let first: UnusedPhysFrame<Size4KiB> = allocator.allocate_frame().expect("allocation failed");
+    let second: UnusedPhysFrame<Size4KiB> = allocator.allocate_frame().expect("allocation failed");
+    assert_eq!(first.start_address(), base.start_address());
+    assert_eq!(second.start_address(), base.start_address() + FRAME_SIZE);
+    assert_eq!(allocator.used_frames(), 2);
+
+    allocator.deallocate_frame(first);
+    assert_eq!(allocator.used_frames(), 1);
+    assert!(!allocator.is_used(base));
+
+    // freed frames are handed out again
+    let reused: UnusedPhysFrame<Size4KiB> = allocator.allocate_frame().expect("allocation failed");
+    assert_eq!(reused.start_address(), base.start_address());
+
+    while FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator).is_some() {}
+    assert_eq!(allocator.free_frames(), 0);
+    assert_eq!(allocator.used_frames(), 100);
+}
@@ -0,0 +1,400 @@
+use x86_64::{
+    structures::paging::{PhysFrame, UnusedPhysFrame, PageSize, Size4KiB,
+        Size2MiB, Size1GiB, FrameAllocator, FrameDeallocator},
+    VirtAddr,
+    PhysAddr,
+};
+
+use bootloader::bootinfo::{MemoryRegionType, MemoryMap};
+
+use super::paging::FrameOwner;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: u64 = 64;
+
+// Order 18 blocks are 2^18 frames, i.e. a single 1GiB frame
+pub const MAX_ORDER: usize = 18;
+const ORDERS: usize = MAX_ORDER + 1;
+
+// Buddy allocator for physically contiguous frames. Each order keeps a
+// bitmap with one bit per naturally aligned block of 2^order frames; a set
+// bit means the block is free and not part of a larger free block.
+pub struct BuddyFrameAllocator {
+    storage: &'static mut [u64],
+    // word offset into storage for each order's bitmap
+    offsets: [usize; ORDERS],
+    // frame number represented by block 0
+    base_frame: u64,
+    frame_count: u64,
+    free_blocks: [usize; ORDERS],
+    total: usize,
+    used: usize,
+    // Where the usable frames came from. Without one every frame in the
+    // range is usable.
+    memory_map: Option<&'static MemoryMap>,
+}
+
+pub fn order_for_frames(count: usize) -> usize {
+    let count = count.max(1);
+    (count.next_power_of_two().trailing_zeros()) as usize
+}
+
+fn order_for_size<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+impl BuddyFrameAllocator {
+    // Number of u64 words of bookkeeping required for frame_count frames
+    pub fn storage_words(frame_count: u64) -> usize {
+        (0..ORDERS)
+            .map(|order| Self::words_for_order(frame_count, order))
+            .sum()
+    }
+
+    fn words_for_order(frame_count: u64, order: usize) -> usize {
+        let blocks = (frame_count + (1 << order) - 1) >> order;
+        ((blocks + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize
+    }
+
+    fn empty(base_frame: u64, frame_count: u64, storage: &'static mut [u64]) -> Self {
+        assert!(base_frame % (1 << MAX_ORDER) == 0,
+                "buddy allocator base must be aligned to the largest order");
+        assert!(storage.len() >= Self::storage_words(frame_count),
+                "buddy allocator storage too small");
+
+        let mut offsets = [0; ORDERS];
+        let mut offset = 0;
+        for order in 0..ORDERS {
+            offsets[order] = offset;
+            offset += Self::words_for_order(frame_count, order);
+        }
+
+        for word in storage.iter_mut() {
+            *word = 0;
+        }
+
+        BuddyFrameAllocator {
+            storage,
+            offsets,
+            base_frame,
+            frame_count,
+            free_blocks: [0; ORDERS],
+            total: 0,
+            used: 0,
+            memory_map: None,
+        }
+    }
+
+    // Creates an allocator where every frame in base..base + frame_count
+    // is free.
+    pub fn new(base: PhysFrame, frame_count: u64, storage: &'static mut [u64]) -> Self {
+        let base_frame = base.start_address().as_u64() / FRAME_SIZE;
+        let mut allocator = Self::empty(base_frame, frame_count, storage);
+        allocator.add_range(0, frame_count);
+        allocator
+    }
+
+    // Seeds the allocator from the bootloader memory map. The bookkeeping
+    // is stored in the first usable region large enough to hold it, and
+    // counted as used.
+    pub unsafe fn init(memory_map: &'static MemoryMap, phys_mem_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+
+        let words = Self::storage_words(frame_count);
+        let storage_bytes = (words * core::mem::size_of::<u64>()) as u64;
+        let storage_frames = (storage_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let storage_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= storage_frames)
+            .expect("no usable region large enough for the buddy allocator");
+
+        let storage_start = storage_region.range.start_frame_number;
+        let storage_end = storage_start + storage_frames;
+        let storage_ptr = (phys_mem_offset + storage_start * FRAME_SIZE).as_mut_ptr::<u64>();
+        let storage = core::slice::from_raw_parts_mut(storage_ptr, words);
+
+        let mut allocator = Self::empty(0, frame_count, storage);
+        allocator.memory_map = Some(memory_map);
+        allocator.total = storage_frames as usize;
+        allocator.used = storage_frames as usize;
+
+        for region in usable_regions() {
+            let start = region.range.start_frame_number;
+            let end = region.range.end_frame_number;
+            if start == storage_start {
+                allocator.add_range(storage_end, end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+
+        allocator
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.used
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.total - self.used
+    }
+
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    // Allocates 2^order physically contiguous frames aligned to their size
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+
+        let found = (order..ORDERS).find(|&o| self.free_blocks[o] > 0)?;
+        let block = self.take_free(found)?;
+
+        // split the block, returning the upper halves to the free lists
+        let mut current = found;
+        while current > order {
+            current -= 1;
+            self.mark_free(block + (1 << current), current);
+        }
+
+        self.used += 1 << order;
+        Some(self.frame_at(block))
+    }
+
+    // Returns a block of 2^order frames, merging it with its buddy for as
+    // long as the buddy is free.
+    pub fn deallocate_order(&mut self, frame: PhysFrame, order: usize) {
+        let index = self.index_of(frame)
+            .expect("deallocated frame is not managed by this allocator");
+        assert!(index % (1 << order) == 0, "frame is not aligned to order {}", order);
+        assert!(!self.overlaps_free(index, order), "double free of frame {:?}", frame);
+
+        self.used -= 1 << order;
+        self.insert(index, order);
+    }
+
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate_order(order_for_frames(count))
+    }
+
+    pub fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        self.deallocate_order(frame, order_for_frames(count))
+    }
+
+    // Adds frames start..end (relative to base) to the free lists using
+    // the largest aligned blocks that fit.
+    fn add_range(&mut self, mut start: u64, end: u64) {
+        let end = end.min(self.frame_count);
+        while start < end {
+            let mut order = MAX_ORDER;
+            while order > 0 && (start % (1 << order) != 0 || start + (1 << order) > end) {
+                order -= 1;
+            }
+            self.total += 1 << order;
+            self.insert(start, order);
+            start += 1 << order;
+        }
+    }
+
+    fn insert(&mut self, mut index: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.frame_count || !self.is_free(buddy, order) {
+                break;
+            }
+            self.clear_free(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.mark_free(index, order);
+    }
+
+    // True if any part of the block is free, either on its own or merged
+    // into a larger free block
+    fn overlaps_free(&self, index: u64, order: usize) -> bool {
+        let enclosing = (order..ORDERS).any(|o| self.is_free(index & !((1 << o) - 1), o));
+        let end = (index + (1 << order)).min(self.frame_count);
+        enclosing || (0..order).any(|o| (index..end).step_by(1 << o).any(|i| self.is_free(i, o)))
+    }
+
+    fn take_free(&mut self, order: usize) -> Option<u64> {
+        let offset = self.offsets[order];
+        let words = Self::words_for_order(self.frame_count, order);
+        for word_index in 0..words {
+            let word = self.storage[offset + word_index];
+            if word != 0 {
+                let bit = word.trailing_zeros() as u64;
+                let index = ((word_index as u64) * BITS_PER_WORD + bit) << order;
+                self.clear_free(index, order);
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    fn bit_position(&self, index: u64, order: usize) -> (usize, u64) {
+        let block = index >> order;
+        (self.offsets[order] + (block / BITS_PER_WORD) as usize, block % BITS_PER_WORD)
+    }
+
+    fn is_free(&self, index: u64, order: usize) -> bool {
+        let (word, bit) = self.bit_position(index, order);
+        self.storage[word] & (1 << bit) != 0
+    }
+
+    fn mark_free(&mut self, index: u64, order: usize) {
+        let (word, bit) = self.bit_position(index, order);
+        self.storage[word] |= 1 << bit;
+        self.free_blocks[order] += 1;
+    }
+
+    fn clear_free(&mut self, index: u64, order: usize) {
+        let (word, bit) = self.bit_position(index, order);
+        self.storage[word] &= !(1 << bit);
+        self.free_blocks[order] -= 1;
+    }
+
+    fn index_of(&self, frame: PhysFrame) -> Option<u64> {
+        let number = frame.start_address().as_u64() / FRAME_SIZE;
+        if number < self.base_frame || number - self.base_frame >= self.frame_count {
+            return None;
+        }
+        Some(number - self.base_frame)
+    }
+
+    fn frame_at(&self, index: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new((self.base_frame + index) * FRAME_SIZE))
+    }
+
+    fn allocate_sized<S: PageSize>(&mut self) -> Option<UnusedPhysFrame<S>> {
+        let frame = self.allocate_order(order_for_size::<S>())?;
+        let frame = PhysFrame::<S>::from_start_address(frame.start_address())
+            .expect("buddy block is not aligned to its size");
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+
+    fn deallocate_sized<S: PageSize>(&mut self, frame: UnusedPhysFrame<S>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_order(frame, order_for_size::<S>());
+    }
+}
+
+// Usable frames are the allocator's whether they are in use or not
+impl FrameOwner for BuddyFrameAllocator {
+    fn owns(&self, frame: PhysFrame) -> bool {
+        let number = frame.start_address().as_u64() / FRAME_SIZE;
+        match self.memory_map {
+            Some(memory_map) => memory_map.iter().any(|region| {
+                region.region_type == MemoryRegionType::Usable
+                    && region.range.start_frame_number <= number
+                    && number < region.range.end_frame_number
+            }),
+            None => self.index_of(frame).is_some(),
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
+        self.allocate_sized()
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        self.allocate_sized()
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size1GiB>> {
+        self.allocate_sized()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size4KiB>) {
+        self.deallocate_sized(frame)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
+        self.deallocate_sized(frame)
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size1GiB>) {
+        self.deallocate_sized(frame)
+    }
+}
+
+#[test_case]
+fn test_buddy_allocator() {
+    static mut STORAGE: [u64; 64] = [0; 64];
+
+    // 4MiB of synthetic physical memory, nothing is ever written to it
+    let base = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+    let mut allocator = BuddyFrameAllocator::new(base, 1024, unsafe { &mut STORAGE });
+    assert_eq!(allocator.total_frames(), 1024);
+    assert_eq!(allocator.free_blocks(10), 1);
+
+    let small: UnusedPhysFrame<Size4KiB> = allocator.allocate_frame()
+        .expect("4KiB allocation failed");
+    let large: UnusedPhysFrame<Size2MiB> = allocator.allocate_frame()
+        .expect("2MiB allocation failed");
+    assert!(large.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(allocator.used_frames(), 1 + 512);
+
+    let huge: Option<UnusedPhysFrame<Size1GiB>> = allocator.allocate_frame();
+    assert!(huge.is_none());
+
+    let dma = allocator.allocate_contiguous(3).expect("contiguous allocation failed");
+    assert!(dma.start_address().is_aligned(4 * FRAME_SIZE));
+    assert_eq!(allocator.used_frames(), 1 + 512 + 4);
+
+    allocator.deallocate_contiguous(dma, 3);
+    allocator.deallocate_frame(large);
+    allocator.deallocate_frame(small);
+
+    // everything coalesces back into a single block
+    assert_eq!(allocator.used_frames(), 0);
+    assert_eq!(allocator.free_blocks(10), 1);
+    assert_eq!(allocator.free_blocks(0), 0);
+}
+
+#[test_case]
+fn test_kernel_frame_allocator() {
+    use super::paging::MEMORY_MANAGER;
+
+    // the kernel's frames come from the buddy allocator seeded at boot
+    let mut guard = MEMORY_MANAGER.lock();
+    let allocator = &mut guard.as_mut().expect("memory manager not installed").frame_allocator;
+    let used = allocator.used_frames();
+
+    let large: UnusedPhysFrame<Size2MiB> = allocator.allocate_frame()
+        .expect("2MiB allocation failed");
+    assert!(large.start_address().is_aligned(Size2MiB::SIZE));
+    assert!(allocator.owns(PhysFrame::containing_address(large.start_address())));
+    assert_eq!(allocator.used_frames(), used + 512);
+
+    // freed one 4KiB frame at a time it merges back into one block
+    let first = PhysFrame::<Size4KiB>::containing_address(large.start_address());
+    for frame in PhysFrame::range(first, first + 512) {
+        allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+    }
+    assert_eq!(allocator.used_frames(), used);
+    let index = first.start_address().as_u64() / FRAME_SIZE;
+    assert!((9..ORDERS).any(|order| allocator.is_free(index & !((1 << order) - 1), order)));
+}
@@ -0,0 +1,129 @@
+use x86_64::structures::paging::{Page, PageSize, Size4KiB};
+use x86_64::VirtAddr;
+use spin::Mutex;
+
+use super::paging::MEMORY_MANAGER;
+use super::address_space::kernel_mapper;
+
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+const GUARD_SIZE: usize = Size4KiB::SIZE as usize;
+// Each stack sits in a slot right above its guard page, so an overflow
+// faults before reaching the stack below
+const SLOT_SIZE: usize = GUARD_SIZE + KERNEL_STACK_SIZE;
+// In the level 4 entry the kernel image lives in, which every address
+// space shares
+const KERNEL_STACK_AREA: u64 = 0x0040_0000_0000;
+const MAX_STACKS: usize = 256;
+
+const FREE: usize = usize::max_value();
+const NO_OWNER: usize = usize::max_value() - 1;
+
+// Proc id using each slot, read by the fault handlers to name the proc
+// that overflowed
+static OWNERS: Mutex<[usize; MAX_STACKS]> = Mutex::new([FREE; MAX_STACKS]);
+
+// A kernel stack mapped in its own slot with an unmapped guard page below
+// it. Unmapped again when dropped. Both wait for the memory manager, so
+// neither may happen with interrupts disabled or the task manager locked.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    // None if every slot is taken or the stack couldn't be mapped
+    pub fn new() -> Option<KernelStack> {
+        let slot = {
+            let mut owners = OWNERS.lock();
+            let slot = owners.iter().position(|&owner| owner == FREE)?;
+            owners[slot] = NO_OWNER;
+            slot
+        };
+
+        let bottom = slot_start(slot) + GUARD_SIZE as u64;
+        let mapped = MEMORY_MANAGER.lock().as_mut().map_or(false, |manager| {
+            let mut mapper = unsafe { kernel_mapper() };
+            manager.request_address_space_at(bottom, KERNEL_STACK_SIZE, &mut mapper).is_ok()
+        });
+        if !mapped {
+            OWNERS.lock()[slot] = FREE;
+            return None;
+        }
+        Some(KernelStack { slot })
+    }
+
+    // Lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        slot_start(self.slot) + GUARD_SIZE as u64
+    }
+
+    // 16 byte aligned end of the stack
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + KERNEL_STACK_SIZE as u64
+    }
+
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(slot_start(self.slot))
+    }
+
+    // Records the proc running on the stack for overflow reports
+    pub fn set_owner(&self, id: usize) {
+        OWNERS.lock()[self.slot] = id;
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        if let Some(manager) = MEMORY_MANAGER.lock().as_mut() {
+            let mut mapper = unsafe { kernel_mapper() };
+            manager.relinquish_address_space(self.bottom(), KERNEL_STACK_SIZE, &mut mapper)
+                .expect("kernel stack was not mapped");
+        }
+        OWNERS.lock()[self.slot] = FREE;
+    }
+}
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(KERNEL_STACK_AREA + (slot * SLOT_SIZE) as u64)
+}
+
+// The proc whose kernel stack lies right above addr's guard page. Called
+// from fault handlers, so it gives up rather than wait for the table.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<usize> {
+    let offset = addr.as_u64().checked_sub(KERNEL_STACK_AREA)? as usize;
+    let slot = offset / SLOT_SIZE;
+    if slot >= MAX_STACKS || offset % SLOT_SIZE >= GUARD_SIZE {
+        return None;
+    }
+    match OWNERS.try_lock()?[slot] {
+        FREE | NO_OWNER => None,
+        id => Some(id),
+    }
+}
+
+#[test_case]
+fn test_kernel_stack() {
+    use super::translate_addr;
+    use super::phys_mem_offset;
+
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    let other = KernelStack::new().expect("could not allocate kernel stack");
+    assert_eq!(stack.top().as_u64() % 16, 0);
+    assert!(other.guard_page().start_address() >= stack.top() || other.top() <= stack.guard_page().start_address());
+    unsafe {
+        *(stack.top() - 8u64).as_mut_ptr::<u64>() = 1;
+        *stack.bottom().as_mut_ptr::<u64>() = 2;
+        assert!(translate_addr(stack.guard_page().start_address(), phys_mem_offset()).is_none());
+    }
+
+    stack.set_owner(42);
+    assert_eq!(guard_page_owner(stack.guard_page().start_address() + 100u64), Some(42));
+    assert_eq!(guard_page_owner(stack.bottom()), None);
+    assert_eq!(guard_page_owner(other.guard_page().start_address()), None);
+
+    let bottom = stack.bottom();
+    let guard = stack.guard_page().start_address();
+    drop(stack);
+    assert!(unsafe { translate_addr(bottom, phys_mem_offset()) }.is_none());
+    assert_eq!(guard_page_owner(guard), None);
+}
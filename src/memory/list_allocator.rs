@@ -1,12 +1,66 @@
-use super::allocator::{Allocation, align_up, Heap, 
+use super::allocator::{Allocation, align_up, Heap, HeapStats,
     move_helper};
 use alloc::alloc::{Layout, AllocErr};
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
 use core::mem::{align_of, size_of};
 
+// Checked heaps fill free memory with POISON and surround every allocation
+// with REDZONE bytes of REDZONE_BYTE
+const POISON: u8 = 0xdd;
+const REDZONE_BYTE: u8 = 0xfb;
+const REDZONE: usize = 16;
+
+// Which hole an allocation is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    // The first hole large enough
+    FirstFit,
+    // The smallest hole large enough
+    BestFit,
+    // The first hole large enough after the previous allocation
+    NextFit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentationStats {
+    pub hole_count: usize,
+    pub largest_hole: usize,
+    pub free_bytes: usize,
+    // Percentage of free memory outside the largest hole, 0 when all of it
+    // can be handed out at once
+    pub external_fragmentation: usize,
+}
+
+// What a checked heap found wrong, with the address it was found at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapCorruption {
+    // A hole outside bottom..bottom + size
+    OutOfBounds(usize),
+    // A hole that starts before the end of the previous one
+    Overlap(usize),
+    // A hole directly after the previous one, they should have been merged
+    Unmerged(usize),
+    // Free memory was written to
+    PoisonOverwritten(usize),
+    // An allocation was written past its bounds
+    RedzoneOverwritten(usize),
+    // The allocation being freed is already free
+    DoubleFree(usize),
+}
+
 pub struct ListHeap {
     bottom: usize,
     size: usize,
+    // Debug mode: the hole list is verified on every allocation and free,
+    // and allocations get redzones
+    checked: bool,
+    policy: FitPolicy,
+    // Where the next fit search starts
+    next_fit: usize,
+    // The heap can be extended up to this size
+    max_size: usize,
+    grow_count: usize,
+    failed_grows: usize,
     holes: HoleList,
 }
 
@@ -23,6 +77,203 @@ impl ListHeap {
 
             self.holes.allocate_first_fit(layout)
     }
+
+    pub fn allocate_best_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let layout = HoleList::adjust_layout(layout);
+        let best = self.holes.iter()
+            .filter(|hole| split_hole(*hole, layout).is_some())
+            .min_by_key(|hole| hole.size)
+            .ok_or(AllocErr)?;
+        self.holes.allocate_from(best.addr, layout)
+    }
+
+    pub fn allocate_next_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let layout = HoleList::adjust_layout(layout);
+        let next_fit = self.next_fit;
+        let fits = |hole: &HoleInfo| split_hole(*hole, layout).is_some();
+        // search from the previous allocation on, then wrap around
+        let hole = self.holes.iter().find(|hole| hole.addr >= next_fit && fits(hole))
+            .or_else(|| self.holes.iter().find(|hole| fits(hole)))
+            .ok_or(AllocErr)?;
+        let ptr = self.holes.allocate_from(hole.addr, layout)?;
+        self.next_fit = ptr.as_ptr() as usize + layout.size();
+        Ok(ptr)
+    }
+
+    // Like Heap::init but with a policy other than first fit
+    pub fn init_with_policy(&mut self, heap_bottom: usize, heap_size: usize, policy: FitPolicy) {
+        self.init(heap_bottom, heap_size);
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    pub fn fragmentation(&self) -> FragmentationStats {
+        let mut stats = FragmentationStats {
+            hole_count: 0,
+            largest_hole: 0,
+            free_bytes: 0,
+            external_fragmentation: 0,
+        };
+        for hole in self.holes.iter() {
+            stats.hole_count += 1;
+            stats.free_bytes += hole.size;
+            stats.largest_hole = core::cmp::max(stats.largest_hole, hole.size);
+        }
+        if stats.free_bytes > 0 {
+            stats.external_fragmentation = 100 - stats.largest_hole * 100 / stats.free_bytes;
+        }
+        stats
+    }
+
+    pub fn top(&self) -> usize {
+        self.bottom + self.size
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.bottom <= addr && addr < self.top()
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = core::cmp::max(max_size, self.size);
+    }
+
+    // How much the heap has to grow by for layout to fit, rounded up to a
+    // multiple of granularity. None if that would exceed the maximum.
+    pub fn growth_for(&self, layout: Layout, granularity: usize) -> Option<usize> {
+        // enough for the worst case alignment padding as well
+        let needed = layout.size() + layout.align() + HoleList::min_size();
+        let by = align_up(core::cmp::max(needed, granularity), granularity);
+        let room = self.max_size - self.size;
+        let by = core::cmp::min(by, room - room % granularity);
+        if by < needed {
+            None
+        } else {
+            Some(by)
+        }
+    }
+
+    // Adds by bytes of memory directly after the heap top as a new hole.
+    // The memory must already be mapped.
+    pub unsafe fn extend(&mut self, by: usize) {
+        let top = self.top();
+        if self.checked {
+            ptr::write_bytes(top as *mut u8, POISON, by);
+        }
+        self.holes.deallocate(NonNull::new_unchecked(top as *mut u8),
+                              Layout::from_size_align_unchecked(by, 1));
+        self.size += by;
+        self.grow_count += 1;
+    }
+
+    // Turns on the debug checks. Has to happen before anything is allocated
+    // so every allocation has redzones.
+    pub fn enable_checks(&mut self) {
+        assert_eq!(self.fragmentation().free_bytes, self.size, "enabling checks on a heap in use");
+        for hole in self.holes.iter() {
+            let header = HoleList::min_size();
+            unsafe { ptr::write_bytes((hole.addr + header) as *mut u8, POISON, hole.size - header) };
+        }
+        self.checked = true;
+        self.holes.poison = true;
+    }
+
+    pub fn checked(&self) -> bool {
+        self.checked
+    }
+
+    // Walks the hole list, which has to be sorted, inside the heap and
+    // free of holes that should have been merged
+    pub fn check(&self) -> Result<(), HeapCorruption> {
+        let mut previous_end = None;
+        for hole in self.holes.iter() {
+            if hole.addr < self.bottom || hole.addr + hole.size > self.top() {
+                return Err(HeapCorruption::OutOfBounds(hole.addr));
+            }
+            match previous_end {
+                Some(end) if hole.addr < end => return Err(HeapCorruption::Overlap(hole.addr)),
+                Some(end) if hole.addr == end => return Err(HeapCorruption::Unmerged(hole.addr)),
+                _ => (),
+            }
+            previous_end = Some(hole.addr + hole.size);
+        }
+        Ok(())
+    }
+
+    // Checks that nothing wrote to free memory. This reads the whole free
+    // heap so it isn't done on every allocation.
+    pub fn check_poison(&self) -> Result<(), HeapCorruption> {
+        for hole in self.holes.iter() {
+            check_bytes(hole.addr + HoleList::min_size(), hole.addr + hole.size, POISON)
+                .map_err(HeapCorruption::PoisonOverwritten)?;
+        }
+        Ok(())
+    }
+
+    // Checks the redzones around an allocation of a checked heap and that
+    // it isn't free already
+    pub fn check_allocation(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), HeapCorruption> {
+        let (inner, front) = redzone_layout(layout);
+        let base = ptr.as_ptr() as usize - front;
+        if self.holes.iter().any(|hole| hole.addr <= base && base < hole.addr + hole.size) {
+            return Err(HeapCorruption::DoubleFree(ptr.as_ptr() as usize));
+        }
+        check_bytes(base, base + front, REDZONE_BYTE)
+            .and_then(|_| check_bytes(base + front + layout.size(), base + inner.size(), REDZONE_BYTE))
+            .map_err(HeapCorruption::RedzoneOverwritten)
+    }
+
+    fn allocate_checked(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        if let Err(error) = self.check() {
+            panic!("heap corrupted: {:?}", error);
+        }
+        let (inner, front) = redzone_layout(layout);
+        let base = self.allocate_unchecked(inner)?.as_ptr() as usize;
+        // the first bytes may have held a hole header
+        if let Err(addr) = check_bytes(base + HoleList::min_size(), base + inner.size(), POISON) {
+            panic!("heap corrupted: {:?}", HeapCorruption::PoisonOverwritten(addr));
+        }
+        unsafe {
+            ptr::write_bytes(base as *mut u8, REDZONE_BYTE, front);
+            ptr::write_bytes((base + front + layout.size()) as *mut u8, REDZONE_BYTE,
+                             inner.size() - front - layout.size());
+        }
+        Ok(NonNull::new((base + front) as *mut u8).unwrap())
+    }
+
+    unsafe fn deallocate_checked(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if let Err(error) = self.check().and_then(|_| self.check_allocation(ptr, layout)) {
+            panic!("heap corrupted: {:?}", error);
+        }
+        let (inner, front) = redzone_layout(layout);
+        let base = ptr.as_ptr() as usize - front;
+        let size = HoleList::adjust_layout(inner).size();
+        ptr::write_bytes(base as *mut u8, POISON, size);
+        self.holes.deallocate(NonNull::new_unchecked(base as *mut u8), HoleList::adjust_layout(inner));
+    }
+
+    fn allocate_unchecked(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        match self.policy {
+            FitPolicy::FirstFit => self.allocate_first_fit(layout),
+            FitPolicy::BestFit => self.allocate_best_fit(layout),
+            FitPolicy::NextFit => self.allocate_next_fit(layout),
+        }
+    }
+
+    pub fn grow_failed(&mut self) {
+        self.failed_grows += 1;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.size,
+            max_size: self.max_size,
+            grow_count: self.grow_count,
+            failed_grows: self.failed_grows,
+        }
+    }
 }
 
 impl ListHeap { 
@@ -30,6 +281,12 @@ impl ListHeap {
         ListHeap {
             bottom: 0,
             size: 0,
+            checked: false,
+            policy: FitPolicy::FirstFit,
+            next_fit: 0,
+            max_size: 0,
+            grow_count: 0,
+            failed_grows: 0,
             holes: HoleList::empty()
         }
     }
@@ -41,6 +298,10 @@ impl Heap for ListHeap {
     fn init(&mut self, heap_bottom: usize, heap_size: usize) {
         self.bottom = heap_bottom;
         self.size = heap_size;
+        self.checked = false;
+        self.policy = FitPolicy::FirstFit;
+        self.next_fit = heap_bottom;
+        self.max_size = heap_size;
         self.holes = unsafe { HoleList::new(heap_bottom, heap_size) };
     }
 
@@ -48,17 +309,30 @@ impl Heap for ListHeap {
         ListHeap {
             bottom: heap_bottom,
             size: heap_size,
+            checked: false,
+            policy: FitPolicy::FirstFit,
+            next_fit: heap_bottom,
+            max_size: heap_size,
+            grow_count: 0,
+            failed_grows: 0,
             holes: unsafe { HoleList::new(heap_bottom, heap_size) }
         }
     }
 
     fn allocate(&mut self, layout: Layout) -> 
         Result<NonNull<u8>, AllocErr> {
-            self.allocate_first_fit(layout)
+            if self.checked {
+                self.allocate_checked(layout)
+            } else {
+                self.allocate_unchecked(layout)
+            }
     }
 
 
     unsafe fn deallocate (&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if self.checked {
+            return self.deallocate_checked(ptr, layout);
+        }
         let mut size = layout.size();
         if size < HoleList::min_size() {
             size = HoleList::min_size();
@@ -91,7 +365,37 @@ fn allocate_first_fit(mut previous: &mut Hole, layout: Layout) -> Result<Allocat
     }
 }
 
-fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
+// Allocates from the hole at hole_addr, which must be large enough
+fn allocate_from(mut previous: &mut Hole, hole_addr: usize, layout: Layout) -> Result<Allocation, AllocErr> {
+    loop {
+        let current = previous.next.as_ref().map(|current| current.info()).ok_or(AllocErr)?;
+        if current.addr == hole_addr {
+            let allocation = split_hole(current, layout).ok_or(AllocErr)?;
+            previous.next = previous.next.as_mut().unwrap().next.take();
+            return Ok(allocation);
+        }
+        previous = move_helper(previous).next.as_mut().unwrap();
+    }
+}
+
+// The layout a checked heap allocates to fit layout between redzones, and
+// the offset of the allocation in it
+fn redzone_layout(layout: Layout) -> (Layout, usize) {
+    let front = align_up(REDZONE, layout.align());
+    let inner = Layout::from_size_align(front + layout.size() + REDZONE, layout.align()).unwrap();
+    (inner, front)
+}
+
+// Returns the address of the first byte in start..end that isn't value
+fn check_bytes(start: usize, end: usize, value: u8) -> Result<(), usize> {
+    match (start..end).find(|&addr| unsafe { *(addr as *const u8) } != value) {
+        Some(addr) => Err(addr),
+        None => Ok(()),
+    }
+}
+
+// With poison set the headers of holes merged into others are poisoned
+fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize, poison: bool) {
     loop {
         let hole_addr = if hole.size == 0 {
             0
@@ -105,6 +409,9 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
             Some(next) if hole_addr + hole.size == addr && addr + size == next.addr => {
                 hole.size += size + next.size;
                 hole.next = hole.next.as_mut().unwrap().next.take();
+                if poison {
+                    unsafe { ptr::write_bytes(next.addr as *mut u8, POISON, HoleList::min_size()) };
+                }
             }
             _ if hole_addr + hole.size == addr => {
                 hole.size += size;
@@ -113,6 +420,9 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
             Some(next) if addr + size == next.addr => {
                 hole.next = hole.next.as_mut().unwrap().next.take();
                 size += next.size;
+                if poison {
+                    unsafe { ptr::write_bytes(next.addr as *mut u8, POISON, HoleList::min_size()) };
+                }
                 continue;
             }
             Some(next) if next.addr <= addr => {
@@ -156,7 +466,22 @@ pub struct HoleInfo {
 }
 
 struct HoleList {
-    first: Hole
+    first: Hole,
+    poison: bool,
+}
+
+struct HoleIter<'a> {
+    current: Option<&'a Hole>,
+}
+
+impl<'a> Iterator for HoleIter<'a> {
+    type Item = HoleInfo;
+
+    fn next(&mut self) -> Option<HoleInfo> {
+        let hole = self.current?;
+        self.current = hole.next.as_ref().map(|next| &**next);
+        Some(hole.info())
+    }
 }
 
 impl HoleList {
@@ -165,7 +490,8 @@ impl HoleList {
             first: Hole {
                 size: 0,
                 next: None,
-            }
+            },
+            poison: false,
         }
     }
 
@@ -183,7 +509,8 @@ impl HoleList {
             first: Hole {
                 size: 0,
                 next: Some(&mut *ptr)
-                }
+                },
+            poison: false,
         }
     }
 
@@ -191,23 +518,45 @@ impl HoleList {
         size_of::<usize>() * 2
     }
 
+    // Rounds layout up to something that can become a hole when freed
+    fn adjust_layout(layout: Layout) -> Layout {
+        let size = core::cmp::max(layout.size(), Self::min_size());
+        let size = align_up(size, align_of::<Hole>());
+        Layout::from_size_align(size, layout.align()).unwrap()
+    }
+
+    fn iter(&self) -> HoleIter {
+        HoleIter { current: self.first.next.as_ref().map(|hole| &**hole) }
+    }
+
+    fn allocate_from(&mut self, hole_addr: usize, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        allocate_from(&mut self.first, hole_addr, layout).map(|allocation| {
+            self.release_padding(&allocation);
+            NonNull::new(allocation.info.addr as *mut u8).unwrap()
+        })
+    }
+
+    fn release_padding(&mut self, allocation: &Allocation) {
+        if let Some(padding) = allocation.front_padding {
+            deallocate(&mut self.first, padding.addr, padding.size, self.poison);
+        }
+        if let Some(padding) = allocation.back_padding {
+            deallocate(&mut self.first, padding.addr, padding.size, self.poison);
+        }
+    }
+
     pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
 
         assert!(layout.size() >= Self::min_size());
 
         allocate_first_fit(&mut self.first, layout).map(|allocation| {
-            if let Some(padding) = allocation.front_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
-            }
-            if let Some(padding) = allocation.back_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
-            }
+            self.release_padding(&allocation);
             NonNull::new(allocation.info.addr as *mut u8).unwrap()
         })
     }
 
     pub fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        deallocate(&mut self.first, ptr.as_ptr() as usize, layout.size())
+        deallocate(&mut self.first, ptr.as_ptr() as usize, layout.size(), self.poison)
     }
 }
 
@@ -256,3 +605,93 @@ fn split_hole(hole: HoleInfo, required_layout: Layout) -> Option<Allocation> {
         back_padding
     })
 }
+
+#[test_case]
+fn test_fit_policies() {
+    use alloc::vec::Vec;
+
+    let mut buffer: Vec<u64> = Vec::with_capacity(512);
+    let bottom = buffer.as_mut_ptr() as usize;
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+    // leaves holes of 256 and 128 bytes in front of the rest of the heap
+    let fragment = |policy| {
+        let mut heap = ListHeap::empty();
+        heap.init_with_policy(bottom, 4096, policy);
+        let sizes = [64, 256, 64, 128, 64];
+        let ptrs: Vec<NonNull<u8>> = sizes.iter()
+            .map(|&size| heap.allocate(layout(size)).expect("allocation failed"))
+            .collect();
+        unsafe {
+            heap.deallocate(ptrs[1], layout(256));
+            heap.deallocate(ptrs[3], layout(128));
+        }
+        heap
+    };
+
+    let mut heap = fragment(FitPolicy::FirstFit);
+    let stats = heap.fragmentation();
+    assert_eq!((stats.hole_count, stats.largest_hole, stats.free_bytes), (3, 3520, 3904));
+    assert_eq!(stats.external_fragmentation, 10);
+    assert_eq!(heap.allocate(layout(100)).unwrap().as_ptr() as usize, bottom + 64);
+
+    let mut heap = fragment(FitPolicy::BestFit);
+    assert_eq!(heap.allocate(layout(100)).unwrap().as_ptr() as usize, bottom + 384);
+    assert_eq!(heap.fragmentation().hole_count, 3);
+
+    let mut heap = fragment(FitPolicy::NextFit);
+    assert_eq!(heap.allocate(layout(100)).unwrap().as_ptr() as usize, bottom + 576);
+    assert_eq!(heap.policy(), FitPolicy::NextFit);
+}
+
+#[test_case]
+fn test_heap_checks() {
+    use alloc::vec::Vec;
+
+    let mut buffer: Vec<u64> = Vec::with_capacity(512);
+    let bottom = buffer.as_mut_ptr() as usize;
+    let mut heap = ListHeap::new(bottom, 4096);
+    heap.enable_checks();
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let a = heap.allocate(layout).expect("allocation failed");
+    let b = heap.allocate(layout).expect("allocation failed");
+    assert_eq!(heap.check(), Ok(()));
+    assert_eq!(heap.check_allocation(a, layout), Ok(()));
+
+    // writing one byte past the end lands in the redzone
+    let past_end = a.as_ptr() as usize + 24;
+    unsafe {
+        let saved = *(past_end as *const u8);
+        *(past_end as *mut u8) = 0;
+        assert_eq!(heap.check_allocation(a, layout), Err(HeapCorruption::RedzoneOverwritten(past_end)));
+        *(past_end as *mut u8) = saved;
+        heap.deallocate(a, layout);
+    }
+    assert_eq!(heap.check_allocation(a, layout), Err(HeapCorruption::DoubleFree(a.as_ptr() as usize)));
+
+    // use after free
+    assert_eq!(heap.check_poison(), Ok(()));
+    let freed = a.as_ptr() as usize + 8;
+    unsafe { *(freed as *mut u8) = 0 };
+    assert_eq!(heap.check_poison(), Err(HeapCorruption::PoisonOverwritten(freed)));
+    unsafe { *(freed as *mut u8) = 0xdd };
+
+    // corrupt the size of the hole a left behind, the next hole starts
+    // at b's end
+    let hole = bottom as *mut usize;
+    let next = bottom + 112;
+    unsafe {
+        let size = *hole;
+        *hole = 2 * 4096;
+        assert_eq!(heap.check(), Err(HeapCorruption::OutOfBounds(bottom)));
+        *hole = 128;
+        assert_eq!(heap.check(), Err(HeapCorruption::Overlap(next)));
+        *hole = 112;
+        assert_eq!(heap.check(), Err(HeapCorruption::Unmerged(next)));
+        *hole = size;
+        heap.deallocate(b, layout);
+    }
+    assert_eq!(heap.check(), Ok(()));
+    assert_eq!(heap.fragmentation().free_bytes, 4096);
+}
@@ -0,0 +1,259 @@
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::PhysAddr;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use spin::Once;
+
+use super::{allocator, phys_mem_offset};
+use super::address_space::{kernel_page_table, is_kernel_entry};
+use super::paging::MEMORY_MANAGER;
+
+const FRAME_SIZE: u64 = 4096;
+
+// Region types of the boot memory map that are counted separately.
+// Anything else is counted as other.
+const REGION_TYPES: [(MemoryRegionType, &str); 13] = [
+    (MemoryRegionType::Usable, "usable"),
+    (MemoryRegionType::InUse, "in use"),
+    (MemoryRegionType::Reserved, "reserved"),
+    (MemoryRegionType::AcpiReclaimable, "ACPI reclaimable"),
+    (MemoryRegionType::AcpiNvs, "ACPI NVS"),
+    (MemoryRegionType::BadMemory, "bad"),
+    (MemoryRegionType::Kernel, "kernel"),
+    (MemoryRegionType::KernelStack, "kernel stack"),
+    (MemoryRegionType::PageTable, "page tables"),
+    (MemoryRegionType::Bootloader, "bootloader"),
+    (MemoryRegionType::FrameZero, "frame zero"),
+    (MemoryRegionType::BootInfo, "boot info"),
+    (MemoryRegionType::Package, "package"),
+];
+const OTHER_REGIONS: usize = REGION_TYPES.len();
+
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+// Keeps the bootloader's memory map around for MemInfo
+pub fn set_memory_map(memory_map: &'static MemoryMap) {
+    MEMORY_MAP.call_once(|| memory_map);
+}
+
+// Where the kernel's memory goes, in bytes. Parts that aren't set up yet
+// are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    // Bytes of each type in the boot memory map, in REGION_TYPES order
+    // followed by everything else
+    pub regions: [u64; OTHER_REGIONS + 1],
+    // Usable RAM as managed by the frame allocator
+    pub usable: u64,
+    pub used: u64,
+    pub free: u64,
+    // Mapped for the kernel heap, of which free is in holes of the list
+    // heap and slab is in slab pages outside of it
+    pub heap_size: u64,
+    pub heap_max_size: u64,
+    pub heap_free: u64,
+    pub slab: u64,
+    // Tables making up the kernel's page table
+    pub kernel_page_tables: u64,
+    // Tables of the user halves of the address spaces with regions mapped
+    // in them, including their level 4 tables. The kernel half they share
+    // is in kernel_page_tables.
+    pub user_page_tables: u64,
+    pub address_spaces: usize,
+    // Regions tracked by the memory manager, over every address space
+    pub region_count: usize,
+    pub region_size: u64,
+    pub mmio_size: u64,
+}
+
+impl MemInfo {
+    pub fn collect() -> MemInfo {
+        let mut info = MemInfo {
+            regions: [0; OTHER_REGIONS + 1],
+            usable: 0,
+            used: 0,
+            free: 0,
+            heap_size: 0,
+            heap_max_size: 0,
+            heap_free: 0,
+            slab: 0,
+            kernel_page_tables: 0,
+            user_page_tables: 0,
+            address_spaces: 0,
+            region_count: 0,
+            region_size: 0,
+            mmio_size: 0,
+        };
+
+        if let Some(memory_map) = MEMORY_MAP.r#try() {
+            for region in memory_map.iter() {
+                let bytes = (region.range.end_frame_number - region.range.start_frame_number) * FRAME_SIZE;
+                info.regions[region_index(region.region_type)] += bytes;
+            }
+        }
+
+        // the heap lock is never held while waiting for the memory manager
+        {
+            let mut heap = allocator::ALLOCATOR.inner().lock();
+            let stats = heap.stats();
+            info.heap_size = stats.size as u64;
+            info.heap_max_size = stats.max_size as u64;
+            info.slab = heap.slab_pages().iter().sum::<usize>() as u64 * FRAME_SIZE;
+            info.heap_free = heap.list_heap().fragmentation().free_bytes as u64;
+        }
+
+        if let Some(manager) = MEMORY_MANAGER.lock().as_ref() {
+            info.usable = manager.frame_allocator.total_frames() as u64 * FRAME_SIZE;
+            info.used = manager.frame_allocator.used_frames() as u64 * FRAME_SIZE;
+            info.free = manager.frame_allocator.free_frames() as u64 * FRAME_SIZE;
+            let regions = manager.get_used_regions();
+            info.region_count = regions.len();
+            info.region_size = regions.iter().map(|region| region.size as u64).sum();
+            info.mmio_size = manager.mmio_regions().iter().map(|mmio| mmio.region.size as u64).sum();
+            let kernel_table = kernel_page_table().start_address();
+            info.kernel_page_tables = unsafe { count_tables(kernel_table, 4) } * FRAME_SIZE;
+
+            // an address space is only known to the manager through its
+            // regions, which are dropped before it is destroyed
+            let mut spaces: Vec<PhysAddr> = regions.iter()
+                .map(|region| region.page_table)
+                .filter(|&table| table != kernel_table)
+                .collect();
+            spaces.sort();
+            spaces.dedup();
+            info.address_spaces = spaces.len();
+            info.user_page_tables = spaces.iter()
+                .map(|&table| unsafe { count_user_tables(table) })
+                .sum::<u64>() * FRAME_SIZE;
+        }
+        info
+    }
+
+    pub fn region_bytes(&self, region_type: MemoryRegionType) -> u64 {
+        self.regions[region_index(region_type)]
+    }
+
+    // Every byte the boot memory map describes
+    pub fn total(&self) -> u64 {
+        self.regions.iter().sum()
+    }
+}
+
+fn region_index(region_type: MemoryRegionType) -> usize {
+    REGION_TYPES.iter()
+        .position(|&(known, _)| known == region_type)
+        .unwrap_or(OTHER_REGIONS)
+}
+
+// Counts the table at table_addr and every table below it
+unsafe fn count_tables(table_addr: PhysAddr, level: u32) -> u64 {
+    let table = &*(phys_mem_offset() + table_addr.as_u64()).as_ptr::<PageTable>();
+    let mut count = 1;
+    if level > 1 {
+        for entry in table.iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+                count += count_tables(entry.addr(), level - 1);
+            }
+        }
+    }
+    count
+}
+
+// Counts the level 4 table at table_addr and the tables below its entries
+// that aren't shared with the kernel
+unsafe fn count_user_tables(table_addr: PhysAddr) -> u64 {
+    let table = &*(phys_mem_offset() + table_addr.as_u64()).as_ptr::<PageTable>();
+    let mut count = 1;
+    for (index, entry) in table.iter().enumerate() {
+        if !is_kernel_entry(index) && entry.flags().contains(PageTableFlags::PRESENT) {
+            count += count_tables(entry.addr(), 3);
+        }
+    }
+    count
+}
+
+// Sizes rounded down to KiB
+struct KiB(u64);
+
+impl fmt::Display for KiB {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} KiB", self.0 / 1024)
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Memory: {} usable, {} used, {} free",
+                 KiB(self.usable), KiB(self.used), KiB(self.free))?;
+        writeln!(f, "Boot memory map: {}", KiB(self.total()))?;
+        for (&(_, name), &bytes) in REGION_TYPES.iter().zip(self.regions.iter()) {
+            if bytes > 0 {
+                writeln!(f, "    {}: {}", name, KiB(bytes))?;
+            }
+        }
+        if self.regions[OTHER_REGIONS] > 0 {
+            writeln!(f, "    other: {}", KiB(self.regions[OTHER_REGIONS]))?;
+        }
+        writeln!(f, "Kernel heap: {} of {} max, {} free, {} in slabs",
+                 KiB(self.heap_size), KiB(self.heap_max_size), KiB(self.heap_free), KiB(self.slab))?;
+        writeln!(f, "Page tables: {} kernel, {} in {} address spaces",
+                 KiB(self.kernel_page_tables), KiB(self.user_page_tables), self.address_spaces)?;
+        write!(f, "Memory regions: {} covering {}, {} MMIO",
+               self.region_count, KiB(self.region_size), KiB(self.mmio_size))
+    }
+}
+
+#[test_case]
+fn test_meminfo() {
+    use alloc::format;
+
+    let info = MemInfo::collect();
+    assert!(info.usable > 0);
+    assert_eq!(info.used + info.free, info.usable);
+    assert_eq!(info.region_bytes(MemoryRegionType::Usable), info.usable);
+    assert!(info.total() >= info.usable);
+    assert!(info.heap_free <= info.heap_size && info.heap_size <= info.heap_max_size);
+    // at least one table on every level
+    assert!(info.kernel_page_tables >= 4 * FRAME_SIZE);
+    assert!(info.region_count > 0);
+
+    // frames mapped for a region are counted as used
+    let mut mapper = unsafe { super::paging::active_page_table() };
+    let addr = x86_64::VirtAddr::new(0x0a80000000);
+    MEMORY_MANAGER.lock().as_mut().unwrap().request_address_space_at(addr, 4 * 4096, &mut mapper)
+        .expect("could not request address space");
+    let after = MemInfo::collect();
+    assert_eq!(after.used + after.free, after.usable);
+    assert!(after.used > info.used);
+    assert_eq!(after.region_count, info.region_count + 1);
+    MEMORY_MANAGER.lock().as_mut().unwrap().relinquish_address_space(addr, 4 * 4096, &mut mapper)
+        .expect("could not relinquish address space");
+
+    // a process address space adds its user tables, one on every level for
+    // a single page
+    let user_addr = (1..256u64)
+        .map(|index| x86_64::VirtAddr::new(index << 39))
+        .find(|&addr| super::address_space::is_user_address(addr))
+        .expect("no free level 4 entry");
+    let space = {
+        let mut guard = MEMORY_MANAGER.lock();
+        let manager = guard.as_mut().unwrap();
+        let space = super::AddressSpace::new(&mut manager.frame_allocator)
+            .expect("could not create address space");
+        let mut space_mapper = unsafe { space.page_table() };
+        manager.request_user_address_space_at(user_addr, 4096, &mut space_mapper)
+            .expect("could not map user page");
+        space
+    };
+    let with_space = MemInfo::collect();
+    assert_eq!(with_space.address_spaces, info.address_spaces + 1);
+    assert!(with_space.user_page_tables >= info.user_page_tables + 4 * FRAME_SIZE);
+    MEMORY_MANAGER.lock().as_mut().unwrap().destroy_address_space(space);
+    assert_eq!(MemInfo::collect().address_spaces, info.address_spaces);
+
+    let report = format!("{}", info);
+    assert!(report.starts_with("Memory: "));
+    assert!(report.contains("    usable: "));
+}
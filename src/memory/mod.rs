@@ -1,79 +1,155 @@
 use x86_64::{
-    structures::paging::{PageTable, OffsetPageTable, PhysFrame, 
-        UnusedPhysFrame, Size4KiB, FrameAllocator},
+    structures::paging::{PageTable, PhysFrame, PageTableFlags},
     VirtAddr,
     PhysAddr,
 };
 
-use bootloader::bootinfo::{MemoryRegionType, MemoryMap};
-
 pub mod allocator;
 mod list_allocator;
 pub mod paging;
+pub mod bitmap_allocator;
+pub mod buddy_allocator;
+mod slab_allocator;
+pub mod tracking;
+pub mod address_space;
+pub mod kernel_stack;
+pub mod meminfo;
+pub mod page_table_dump;
+pub mod pat;
+
+pub use bitmap_allocator::BitmapFrameAllocator;
+pub use buddy_allocator::BuddyFrameAllocator;
+pub use address_space::AddressSpace;
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Virtual address at which the bootloader mapped all of physical memory
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_phys_mem_offset(offset: VirtAddr) {
+    PHYS_MEM_OFFSET.store(offset.as_u64(), Ordering::SeqCst);
+}
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize
+pub fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::SeqCst))
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0
-        }
-    }
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
 
-    fn usable_frames(&self) -> impl Iterator<Item = UnusedPhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+    let (level_4_table_frame, _) = Cr3::read();
+    walk_page_table(level_4_table_frame, addr, physical_memory_offset)
+        .map(|translation| translation.phys)
+}
 
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+// Size of the page a virtual address is mapped with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
 
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
-        frames.map(|f| unsafe { UnusedPhysFrame::new(f) })
+impl MappingSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Size4KiB => 4096,
+            MappingSize::Size2MiB => 2 * 1024 * 1024,
+            MappingSize::Size1GiB => 1024 * 1024 * 1024,
+        }
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+impl fmt::Display for MappingSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MappingSize::Size4KiB => write!(f, "4KiB"),
+            MappingSize::Size2MiB => write!(f, "2MiB"),
+            MappingSize::Size1GiB => write!(f, "1GiB"),
+        }
     }
 }
 
-
-
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    translate_addr_inner(addr, physical_memory_offset)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    pub size: MappingSize,
+    // The leaf entry's flags restricted by every level above it: a page is
+    // only writable or user accessible if all levels allow it, and not
+    // executable if any level forbids it
+    pub flags: PageTableFlags,
 }
 
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::structures::paging::page_table::FrameError;
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
+// Walks the page table at level_4_table_frame down to the entry mapping
+// addr, which may be a 1GiB or 2MiB page
+pub unsafe fn walk_page_table(level_4_table_frame: PhysFrame, addr: VirtAddr,
+                              physical_memory_offset: VirtAddr) -> Option<Translation> {
     let table_indexes = [
         addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
     ];
-    let mut frame = level_4_table_frame;
-
-    for &index in &table_indexes {
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe {&*table_ptr};
+    let mut table_addr = level_4_table_frame.start_address();
+    let mut inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut no_execute = false;
 
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virt = physical_memory_offset + table_addr.as_u64();
+        let table = &*virt.as_ptr::<PageTable>();
         let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        inherited &= flags;
+        no_execute |= flags.contains(PageTableFlags::NO_EXECUTE);
+
+        let size = match level {
+            1 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappingSize::Size1GiB),
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappingSize::Size2MiB),
+            3 => Some(MappingSize::Size4KiB),
+            _ => None,
         };
+        if let Some(size) = size {
+            return Some(Translation {
+                phys: entry.addr() + (addr.as_u64() & (size.bytes() - 1)),
+                size,
+                flags: effective_flags(flags, inherited, no_execute),
+            });
+        }
+        table_addr = entry.addr();
     }
+    unreachable!("level 1 entries are always leaves")
+}
 
-    Some(frame.start_address() + u64::from(addr.page_offset()))
-
+// Flags of a leaf entry as restricted by the levels above it. inherited
+// holds WRITABLE and USER_ACCESSIBLE if every level allowed them.
+fn effective_flags(leaf: PageTableFlags, inherited: PageTableFlags, no_execute: bool) -> PageTableFlags {
+    let mut flags = leaf - (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE) | inherited;
+    if no_execute {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
 }
 
+#[test_case]
+fn test_walk_page_table() {
+    use x86_64::registers::control::Cr3;
+
+    let offset = phys_mem_offset();
+    let (level_4, _) = Cr3::read();
+
+    // the physical memory mapping may use any page size
+    for &phys in &[0x1000u64, 0x20_1234, 0x80_0000 - 8] {
+        let translation = unsafe { walk_page_table(level_4, offset + phys, offset) }
+            .expect("physical memory not mapped");
+        assert_eq!(translation.phys, PhysAddr::new(phys));
+        assert!(translation.flags.contains(PageTableFlags::PRESENT));
+        assert_eq!(unsafe { translate_addr(offset + phys, offset) }, Some(PhysAddr::new(phys)));
+    }
+
+    let heap = VirtAddr::new(allocator::HEAP_START as u64);
+    let translation = unsafe { walk_page_table(level_4, heap, offset) }.expect("heap not mapped");
+    assert_eq!(translation.size, MappingSize::Size4KiB);
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!translation.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
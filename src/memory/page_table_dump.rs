@@ -0,0 +1,229 @@
+use x86_64::{
+    structures::paging::{PageTable, OffsetPageTable, PageTableFlags},
+    VirtAddr,
+    PhysAddr,
+};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{MappingSize, effective_flags, phys_mem_offset};
+use super::paging::page_table_address;
+use super::address_space::COPY_ON_WRITE;
+use super::pat::PAT_4KIB;
+
+// A run of virtual memory mapped to contiguous physical memory with pages
+// of one size and the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    // In bytes, not necessarily a multiple of the page size when the
+    // mapping was cut by the range being looked at
+    pub size: u64,
+    pub page_size: MappingSize,
+    // Effective flags as in Translation, without ACCESSED, DIRTY and
+    // HUGE_PAGE. For 4KiB pages bit 7 is pat::PAT_4KIB.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    fn continues_with(&self, next: &Mapping) -> bool {
+        self.start.as_u64().wrapping_add(self.size) == next.start.as_u64()
+            && self.phys + self.size == next.phys
+            && self.page_size == next.page_size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(f, "{:#018x}-{:#018x} -> {:#x} {} r{}{}{}",
+               self.start.as_u64(), self.start.as_u64().wrapping_add(self.size), self.phys.as_u64(), self.page_size,
+               flag(PageTableFlags::WRITABLE, 'w'),
+               if self.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+               flag(PageTableFlags::USER_ACCESSIBLE, 'u'))?;
+        if self.flags.contains(PageTableFlags::GLOBAL) {
+            write!(f, " global")?;
+        }
+        if self.flags.contains(PageTableFlags::NO_CACHE) {
+            write!(f, " no-cache")?;
+        }
+        if self.flags.contains(PageTableFlags::WRITE_THROUGH) {
+            write!(f, " write-through")?;
+        }
+        if self.page_size == MappingSize::Size4KiB && self.flags.contains(PAT_4KIB) {
+            write!(f, " pat")?;
+        }
+        if self.flags.contains(COPY_ON_WRITE) {
+            write!(f, " cow")?;
+        }
+        Ok(())
+    }
+}
+
+// The range a walk is restricted to, both ends inclusive so it can reach
+// the top of the address space
+#[derive(Clone, Copy)]
+struct Filter {
+    first: u64,
+    last: u64,
+}
+
+// Calls f with the mappings of mapper's page table that overlap
+// addr..addr + size in address order. Adjacent pages are merged into one
+// mapping where Mapping::continues_with allows, and mappings are cut to the
+// range. Nothing is allocated, so it's usable while debugging the heap.
+pub fn for_each_mapping<F: FnMut(Mapping)>(mapper: &mut OffsetPageTable, addr: VirtAddr, size: u64, mut f: F) {
+    if size == 0 {
+        return;
+    }
+    let filter = Filter {
+        first: addr.as_u64(),
+        last: addr.as_u64().saturating_add(size - 1),
+    };
+    let mut pending: Option<Mapping> = None;
+    let mut merge = |leaf: Mapping| match pending {
+        Some(ref mut run) if run.continues_with(&leaf) => run.size += leaf.size,
+        _ => {
+            if let Some(run) = pending.replace(leaf) {
+                f(run);
+            }
+        },
+    };
+
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    unsafe {
+        walk_table(page_table_address(mapper), 4, 0, inherited, false, filter, &mut merge);
+    }
+    if let Some(run) = pending {
+        f(run);
+    }
+}
+
+// Walks the table at table_addr, which maps the memory from base on with
+// entries of the given level
+unsafe fn walk_table(table_addr: PhysAddr, level: u32, base: u64, inherited: PageTableFlags,
+                     no_execute: bool, filter: Filter, emit: &mut dyn FnMut(Mapping)) {
+    let table = &*(phys_mem_offset() + table_addr.as_u64()).as_ptr::<PageTable>();
+    let entry_size = 4096u64 << (9 * (level - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let start = canonical(base + index as u64 * entry_size);
+        let last = start + (entry_size - 1);
+        let flags = entry.flags();
+        if last < filter.first || start > filter.last || !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let inherited = inherited & flags;
+        let no_execute = no_execute || flags.contains(PageTableFlags::NO_EXECUTE);
+
+        let page_size = match level {
+            3 if flags.contains(PageTableFlags::HUGE_PAGE) => MappingSize::Size1GiB,
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => MappingSize::Size2MiB,
+            1 => MappingSize::Size4KiB,
+            _ => {
+                walk_table(entry.addr(), level - 1, start, inherited, no_execute, filter, emit);
+                continue;
+            },
+        };
+        let first = core::cmp::max(start, filter.first);
+        emit(Mapping {
+            start: VirtAddr::new(first),
+            phys: entry.addr() + (first - start),
+            size: core::cmp::min(last, filter.last) - first + 1,
+            page_size,
+            flags: leaf_flags(effective_flags(flags, inherited, no_execute), page_size),
+        });
+    }
+}
+
+// Accessed and dirty differ between otherwise identical pages and would keep
+// them from merging. The huge page bit is in page_size already, in 4KiB
+// entries the same bit selects the PAT entry and is kept.
+fn leaf_flags(flags: PageTableFlags, page_size: MappingSize) -> PageTableFlags {
+    let flags = flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+    match page_size {
+        MappingSize::Size4KiB => flags,
+        _ => flags - PageTableFlags::HUGE_PAGE,
+    }
+}
+
+// Sign extends bit 47 as the upper half of the address space requires
+fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+// Mappings overlapping addr..addr + size, see for_each_mapping
+pub fn mappings(mapper: &mut OffsetPageTable, addr: VirtAddr, size: u64) -> Vec<Mapping> {
+    let mut mappings = Vec::new();
+    for_each_mapping(mapper, addr, size, |mapping| mappings.push(mapping));
+    mappings
+}
+
+// Prints the mappings overlapping addr..addr + size to the serial port
+pub fn dump_page_table(mapper: &mut OffsetPageTable, addr: VirtAddr, size: u64) {
+    crate::dbg_println!("Page table at {:#x}, {:#x}-{:#x}:", page_table_address(mapper).as_u64(),
+                        addr.as_u64(), addr.as_u64().saturating_add(size));
+    let mut count = 0;
+    for_each_mapping(mapper, addr, size, |mapping| {
+        crate::dbg_println!("  {}", mapping);
+        count += 1;
+    });
+    crate::dbg_println!("{} mappings", count);
+}
+
+// Prints every mapping of the page table
+pub fn dump_all(mapper: &mut OffsetPageTable) {
+    dump_page_table(mapper, VirtAddr::new(0), u64::MAX);
+}
+
+#[test_case]
+fn test_page_table_dump() {
+    use super::paging::{MEMORY_MANAGER, active_page_table};
+
+    let test_addr = VirtAddr::new(0x0b80000000);
+    let mut mapper = unsafe { active_page_table() };
+
+    // physical memory is mapped contiguously, so any piece of it is one
+    // mapping cut to the range
+    let offset = phys_mem_offset();
+    let physical = mappings(&mut mapper, offset + 0x1800u64, 0x2000);
+    assert_eq!(physical.len(), 1);
+    assert_eq!((physical[0].start, physical[0].phys, physical[0].size),
+               (offset + 0x1800u64, PhysAddr::new(0x1800), 0x2000));
+
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+    assert!(mappings(&mut mapper, test_addr, 3 * 4096).is_empty());
+
+    manager.request_address_space_at(test_addr, 3 * 4096, &mut mapper)
+        .expect("could not request address space");
+    let requested = mappings(&mut mapper, test_addr, 4 * 4096);
+    assert_eq!(requested.first().map(|mapping| mapping.start), Some(test_addr));
+    assert_eq!(requested.last().map(|mapping| mapping.end()), Some(test_addr + 3 * 4096u64));
+    for mapping in requested.iter() {
+        assert_eq!(mapping.page_size, MappingSize::Size4KiB);
+        assert!(mapping.flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+        assert!(!mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    }
+    assert_eq!(requested.iter().map(|mapping| mapping.size).sum::<u64>(), 3 * 4096);
+
+    // a hole in the middle leaves two separate mappings
+    manager.relinquish_address_space(test_addr + 4096u64, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    let split: Vec<(VirtAddr, u64)> = mappings(&mut mapper, test_addr, 3 * 4096).iter()
+        .map(|mapping| (mapping.start, mapping.size))
+        .collect();
+    assert_eq!(split, [(test_addr, 4096), (test_addr + 2 * 4096u64, 4096)]);
+
+    manager.relinquish_address_space(test_addr, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    manager.relinquish_address_space(test_addr + 2 * 4096u64, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(mappings(&mut mapper, test_addr, 3 * 4096).is_empty());
+}
@@ -1,14 +1,87 @@
 use x86_64::{
-    structures::paging::{PageTable, OffsetPageTable, 
-        Size4KiB, Mapper, mapper::MapperFlush, mapper::MapToError, FrameAllocator, page::Page, page_table::PageTableFlags},
+    structures::paging::{PageTable, OffsetPageTable, UnusedPhysFrame,
+        Size4KiB, Size2MiB, Mapper, mapper::MapperFlush, mapper::MapToError, FrameAllocator,
+        FrameDeallocator, PageSize, PhysFrame, page::{Page, PageRange},
+        page_table::{PageTableFlags, PageTableEntry}},
     VirtAddr,
     PhysAddr
 };
 
 use alloc::vec::Vec;
-use super::BootInfoFrameAllocator;
+use core::ops::{BitOr, Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
 
-use bootloader::bootinfo::{MemoryRegionType, MemoryMap};
+use super::{BuddyFrameAllocator, AddressSpace, phys_mem_offset, walk_page_table};
+use super::allocator::align_up;
+use super::address_space::{FrameRefs, COPY_ON_WRITE, is_kernel_range, is_user_range, is_user_address, kernel_page_table};
+use super::pat;
+
+// The kernel's memory manager, installed once paging and the heap are set
+// up. Interrupt handlers use this to resolve page faults.
+pub static MEMORY_MANAGER: ManagerLock<Option<MemoryManager<BuddyFrameAllocator>>> = ManagerLock::new(None);
+
+const NO_OWNER: usize = usize::max_value();
+
+// A mutex that remembers which proc holds it, so the page fault handler can
+// tell a fault under its own lock from one that only has to wait
+pub struct ManagerLock<T> {
+    inner: Mutex<T>,
+    owner: AtomicUsize,
+}
+
+pub struct ManagerGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+
+impl<T> ManagerLock<T> {
+    pub const fn new(value: T) -> ManagerLock<T> {
+        ManagerLock {
+            inner: Mutex::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> ManagerGuard<T> {
+        self.owned(self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<ManagerGuard<T>> {
+        self.inner.try_lock().map(|guard| self.owned(guard))
+    }
+
+    fn owned<'a>(&'a self, guard: MutexGuard<'a, T>) -> ManagerGuard<'a, T> {
+        self.owner.store(crate::task::running_id(), Ordering::SeqCst);
+        ManagerGuard { guard, owner: &self.owner }
+    }
+
+    // True if the running proc holds the lock
+    pub fn held_by_current(&self) -> bool {
+        self.owner.load(Ordering::SeqCst) == crate::task::running_id()
+    }
+}
+
+impl<'a, T> Deref for ManagerGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for ManagerGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for ManagerGuard<'a, T> {
+    // runs before the mutex is unlocked
+    fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::SeqCst);
+    }
+}
 
 unsafe fn active_level_4_table(phys_mem_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
@@ -26,29 +99,370 @@ pub unsafe fn offset_page_table(phys_mem_offset: VirtAddr) -> OffsetPageTable<'s
     OffsetPageTable::new(level_4_table, phys_mem_offset)
 }
 
+pub unsafe fn active_page_table() -> OffsetPageTable<'static> {
+    offset_page_table(phys_mem_offset())
+}
+
+// Physical address of the level 4 table behind mapper
+pub fn page_table_address(mapper: &mut OffsetPageTable) -> PhysAddr {
+    let table = mapper.level_4_table() as *mut PageTable as u64;
+    PhysAddr::new(table - phys_mem_offset().as_u64())
+}
+
+// The level 4 table regions at addr are tracked under. The kernel half is
+// shared by every address space, so its regions belong to the kernel's page
+// table whichever mapper they were requested through.
+fn region_table(addr: VirtAddr, mapper: &mut OffsetPageTable) -> PhysAddr {
+    if is_user_address(addr) {
+        page_table_address(mapper)
+    } else {
+        kernel_page_table().start_address()
+    }
+}
+
+// map_to doesn't set USER_ACCESSIBLE on intermediate tables, but ring 3
+// can only reach a page if every level allows it.
+unsafe fn set_user_accessible_parents(mapper: &mut OffsetPageTable, page: Page<Size4KiB>) {
+    let offset = phys_mem_offset();
+    let mut table = mapper.level_4_table();
+
+    for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &mut table[index];
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        let next = offset + entry.addr().as_u64();
+        table = &mut *next.as_mut_ptr::<PageTable>();
+    }
+    x86_64::instructions::tlb::flush(page.start_address());
+}
+
+const PAGES_PER_HUGE_PAGE: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
+// The entry for addr in the table at level, if the tables leading to it
+// exist and none of them is a huge page
+unsafe fn table_entry(mapper: &mut OffsetPageTable, addr: VirtAddr, level: usize) -> Option<&'static mut PageTableEntry> {
+    let offset = phys_mem_offset();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table: *mut PageTable = mapper.level_4_table();
+
+    for &index in &indexes[..4 - level] {
+        let entry = &(*table)[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = (offset + entry.addr().as_u64()).as_mut_ptr();
+    }
+    Some(&mut (*table)[indexes[4 - level]])
+}
+
+// The level 1 entry mapping page, if the tables leading to it exist
+unsafe fn page_entry(mapper: &mut OffsetPageTable, page: Page<Size4KiB>) -> Option<&'static mut PageTableEntry> {
+    table_entry(mapper, page.start_address(), 1)
+}
+
+// The level 2 entry if it maps page as a huge page
+unsafe fn huge_page_entry(mapper: &mut OffsetPageTable, page: Page<Size2MiB>) -> Option<&'static mut PageTableEntry> {
+    table_entry(mapper, page.start_address(), 2)
+        .filter(|entry| entry.flags().contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE))
+}
+
+fn is_mapped(mapper: &mut OffsetPageTable, page: Page<Size4KiB>) -> bool {
+    let level_4 = PhysFrame::containing_address(page_table_address(mapper));
+    unsafe { walk_page_table(level_4, page.start_address(), phys_mem_offset()).is_some() }
+}
+
+// Locks the manager for the page fault handler. While another proc holds
+// it the faulting one yields until it is released. A fault taken while the
+// running proc holds it comes from the memory manager itself and can't be
+// resolved, so None is returned.
+fn lock_for_fault() -> Option<ManagerGuard<'static, Option<MemoryManager<BuddyFrameAllocator>>>> {
+    loop {
+        if let Some(guard) = MEMORY_MANAGER.try_lock() {
+            return Some(guard);
+        }
+        if MEMORY_MANAGER.held_by_current() || !crate::scheduler::enabled() {
+            return None;
+        }
+        crate::scheduler::yield_now();
+    }
+}
+
+// Called from the page fault handler. Returns true if the fault was
+// resolved by backing a demand paged region.
+pub fn demand_page(addr: VirtAddr) -> bool {
+    let mut guard = match lock_for_fault() {
+        Some(guard) => guard,
+        None => return false,
+    };
+
+    match guard.as_mut() {
+        Some(manager) => {
+            let mut mapper = unsafe { active_page_table() };
+            manager.handle_page_fault(addr, &mut mapper)
+        },
+        None => false,
+    }
+}
+
+// Called from the page fault handler for writes to read only pages.
+// Returns true if the page was copy-on-write.
+pub fn copy_on_write(addr: VirtAddr) -> bool {
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = match lock_for_fault() {
+        Some(guard) => guard,
+        None => return false,
+    };
+
+    match guard.as_mut() {
+        Some(manager) => manager.handle_write_fault(addr, &mut mapper),
+        None => false,
+    }
+}
+
+// Access rights of a region, turned into page table flags when its pages
+// are mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const READ: Protection = Protection(1 << 0);
+    pub const WRITE: Protection = Protection(1 << 1);
+    pub const EXECUTE: Protection = Protection(1 << 2);
+    pub const USER: Protection = Protection(1 << 3);
+    pub const NO_CACHE: Protection = Protection(1 << 4);
+    pub const WRITE_THROUGH: Protection = Protection(1 << 5);
+
+    pub const fn empty() -> Protection {
+        Protection(0)
+    }
+
+    pub fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn page_table_flags(self) -> PageTableFlags {
+        // pages can't be mapped without being readable
+        let mut flags = PageTableFlags::PRESENT;
+        if self.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.contains(Protection::USER) {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.contains(Protection::NO_CACHE) {
+            flags |= PageTableFlags::NO_CACHE;
+        }
+        if self.contains(Protection::WRITE_THROUGH) {
+            flags |= PageTableFlags::WRITE_THROUGH;
+        }
+        if !self.contains(Protection::EXECUTE) && no_execute_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+impl BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, other: Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
+
+// NO_EXECUTE is a reserved bit until EFER.NXE is set
+fn no_execute_enabled() -> bool {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+pub fn enable_no_execute() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    unsafe {
+        Efer::write(Efer::read() | EferFlags::NO_EXECUTE_ENABLE);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct MemoryRegion {
     pub start: VirtAddr,
     pub size: usize,
+    // Pages are only backed by a frame once they are first accessed
+    pub demand_paged: bool,
+    // Physical address of the level 4 table the region is mapped in
+    pub page_table: PhysAddr,
+    pub protection: Protection,
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.start + self.size
+    }
+
+    // True if any page of the region lies in start..end
+    pub fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start.align_down(Size4KiB::SIZE) < end && start < self.end()
+    }
+
+    // End of the last page the region covers
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new(align_up(self.start.as_u64() as usize + self.size, Size4KiB::SIZE as usize) as u64)
+    }
+}
+
+// Caching of device memory mapped with ioremap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+    // Uncached if the PAT couldn't be set up
+    WriteCombining,
 }
 
+impl CacheMode {
+    // Flags of a 4KiB page table entry selecting the mode, see pat::init
+    pub fn page_table_flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if pat::write_combining_enabled() => pat::PAT_4KIB,
+            CacheMode::Uncached | CacheMode::WriteCombining =>
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+// Physical memory that isn't RAM, like device registers or a framebuffer,
+// mapped with ioremap. The frames aren't the frame allocator's and are
+// never handed to it.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegion {
+    pub region: MemoryRegion,
+    pub phys: PhysAddr,
+    pub cache: CacheMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    // Part of the range is already in use
+    Overlap,
+    // Part of the range isn't mapped or tracked
+    NotMapped,
+    // No frame left to back a page
+    OutOfFrames,
+    // No frame left for a page table
+    PageTableAllocFailed,
+    // No free range of the requested size in the arena
+    OutOfAddressSpace,
+    // The mapping can't be made this way, e.g. huge pages for user memory
+    // or kernel memory outside the kernel half
+    Unsupported,
+    // Refused by W^X enforcement
+    WritableAndExecutable,
+    // The physical and virtual address differ in their offset into a page
+    Misaligned,
+    // The physical range is RAM the frame allocator hands out
+    UsableMemory,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MemoryError {
+    fn from(error: MapToError<S>) -> MemoryError {
+        match error {
+            MapToError::FrameAllocationFailed => MemoryError::PageTableAllocFailed,
+            // a huge page or another mapping is in the way
+            _ => MemoryError::Overlap,
+        }
+    }
+}
+
+// Every page that overlaps addr..addr + size
+fn page_range(addr: VirtAddr, size: usize) -> PageRange<Size4KiB> {
+    let end = align_up(addr.as_u64() as usize + size.max(1), Size4KiB::SIZE as usize);
+    Page::range(Page::containing_address(addr), Page::containing_address(VirtAddr::new(end as u64)))
+}
+
+// Where request_address_space picks addresses unless told otherwise. It
+// lies in the level 4 entry of the kernel image, above the kernel stacks,
+// so every address space sees what is mapped there.
+pub const DEFAULT_ARENA_START: u64 = 0x0000_0050_0000_0000;
+pub const DEFAULT_ARENA_SIZE: usize = 0x0000_0010_0000_0000;
+
 // TODO: Move MemoryManager and MemoryRegion to memory module root
 // FIXME: Support other page sizes?
 pub struct MemoryManager<A: FrameAllocator<Size4KiB>> {
     pub frame_allocator: A,
-    // FIXME: This most likely needs to be a hash map, unsure of what the
-    // key needs to be though.
-    used_memory_regions: Vec<MemoryRegion>
+    // Frames mapped by more than one address space after a fork
+    pub frame_refs: FrameRefs,
+    // Sorted by start address
+    used_memory_regions: Vec<MemoryRegion>,
+    // Kept apart from the regions above, which own their frames
+    mmio_regions: Vec<MmioRegion>,
+    arena_start: VirtAddr,
+    arena_size: usize,
+    // Debug builds refuse regions that are writable and executable unless
+    // told otherwise
+    enforce_wx: bool,
 }
 
-impl<A: FrameAllocator<Size4KiB>> MemoryManager<A> {
+impl<A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>> MemoryManager<A> {
     pub fn new(allocator: A) -> MemoryManager<A> {
         MemoryManager {
             frame_allocator: allocator,
-            used_memory_regions: Vec::new()
+            frame_refs: FrameRefs::new(),
+            used_memory_regions: Vec::new(),
+            mmio_regions: Vec::new(),
+            arena_start: VirtAddr::new(DEFAULT_ARENA_START),
+            arena_size: DEFAULT_ARENA_SIZE,
+            enforce_wx: cfg!(debug_assertions),
         }
     }
 
+    // Whether requests for writable and executable regions fail
+    #[cfg(debug_assertions)]
+    pub fn set_enforce_wx(&mut self, enforce: bool) {
+        self.enforce_wx = enforce;
+    }
+
+    // Kernel memory anywhere but the kernel half would only be mapped in
+    // one address space, user memory in the kernel half in every one
+    fn check_kernel_half(addr: VirtAddr, size: usize, protection: Protection) -> Result<(), MemoryError> {
+        let allowed = if protection.contains(Protection::USER) {
+            is_user_range(addr, size)
+        } else {
+            is_kernel_range(addr, size)
+        };
+        if !allowed {
+            return Err(MemoryError::Unsupported);
+        }
+        Ok(())
+    }
+
+    fn check_protection(&self, protection: Protection) -> Result<(), MemoryError> {
+        let wx = Protection::WRITE | Protection::EXECUTE;
+        if cfg!(debug_assertions) && self.enforce_wx && protection.contains(wx) {
+            return Err(MemoryError::WritableAndExecutable);
+        }
+        Ok(())
+    }
+
+    // Sets the range request_address_space allocates from
+    pub fn set_arena(&mut self, start: VirtAddr, size: usize) {
+        self.arena_start = start;
+        self.arena_size = size;
+    }
+
+    fn insert_region(&mut self, region: MemoryRegion) {
+        let index = self.used_memory_regions
+            .binary_search_by_key(&region.start, |r| r.start)
+            .unwrap_or_else(|index| index);
+        self.used_memory_regions.insert(index, region);
+    }
+
     pub fn get_used_regions(&self) -> &Vec<MemoryRegion> {
         &self.used_memory_regions
     }
@@ -57,70 +471,653 @@ impl<A: FrameAllocator<Size4KiB>> MemoryManager<A> {
         // Heap allocation happens before the memory manager 
         // so the memory manager can use dynamic types,
         // but we should keep track of the region being used.
-        self.used_memory_regions.push(region);
-    }
-
-    fn map<M: Mapper<Size4KiB>>(&mut self, addr: VirtAddr, size: usize, mapper: &mut M) 
-        -> Result<(), MapToError<Size4KiB>> {
-            // FIXME: Proper error handling
-            // FIXME: BUG not allocating frames for single pages. 
-            // Only allocating if pages are > 1 (i.e. size > 4kb)
-            let start_page = Page::containing_address(addr);
-
-            let end_page = Page::containing_address(VirtAddr::new(addr.as_u64() + size as u64));
-            let page_range = Page::range(start_page, end_page);
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            for p in page_range {
-                if let Some(frame) = self.frame_allocator.allocate_frame() {
-                    mapper.map_to(p, frame, flags, &mut self.frame_allocator)?.flush();
-                } else {
-                    return Err(MapToError::FrameAllocationFailed);
+        self.insert_region(region);
+    }
+
+    // Maps size more bytes directly after the region ending at end and adds
+    // them to it. Nothing is allocated from the heap, which lets the heap
+    // grow itself through this.
+    pub fn grow_region(&mut self, end: VirtAddr, size: usize, mapper: &mut OffsetPageTable)
+        -> Result<(), MemoryError> {
+        let page_table = region_table(end, mapper);
+        let protection = match self.used_memory_regions.iter()
+            .find(|region| region.page_table == page_table && region.start + region.size == end) {
+            Some(region) => region.protection,
+            None => return Err(MemoryError::NotMapped),
+        };
+        if self.overlaps(end, size, page_table) {
+            return Err(MemoryError::Overlap);
+        }
+
+        self.map(end, size, protection.page_table_flags(), mapper)?;
+        self.extend_region(end, size, mapper)
+    }
+
+    // Adds size bytes directly after the region ending at end to it. The
+    // caller has mapped them already, as the heap does when it grows while
+    // the manager is busy.
+    pub fn extend_region(&mut self, end: VirtAddr, size: usize, mapper: &mut OffsetPageTable)
+        -> Result<(), MemoryError> {
+        let page_table = region_table(end, mapper);
+        if self.overlaps(end, size, page_table) {
+            return Err(MemoryError::Overlap);
+        }
+        let region = self.used_memory_regions.iter_mut()
+            .find(|region| region.page_table == page_table && region.start + region.size == end)
+            .ok_or(MemoryError::NotMapped)?;
+        region.size += size;
+        Ok(())
+    }
+
+    // True if a tracked region of the page table overlaps the range
+    fn overlaps(&self, addr: VirtAddr, size: usize, page_table: PhysAddr) -> bool {
+        let pages = page_range(addr, size);
+        let (start, end) = (pages.start.start_address(), pages.end.start_address());
+        self.used_memory_regions.iter()
+            .chain(self.mmio_regions.iter().map(|mmio| &mmio.region))
+            .any(|region| region.page_table == page_table && region.overlaps(start, end))
+    }
+
+    fn map(&mut self, addr: VirtAddr, size: usize, flags: PageTableFlags, mapper: &mut OffsetPageTable) 
+        -> Result<(), MemoryError> {
+            let pages = page_range(addr, size);
+            // pages mapped without the manager knowing count as well
+            if pages.clone().any(|p| is_mapped(mapper, p)) {
+                return Err(MemoryError::Overlap);
+            }
+
+            for (mapped, p) in pages.clone().enumerate() {
+                let result = match self.frame_allocator.allocate_frame() {
+                    Some(frame) => {
+                        let phys_frame = *frame;
+                        mapper.map_to(p, frame, flags, &mut self.frame_allocator)
+                            .map(|flush| flush.flush())
+                            .map_err(|error| {
+                                // map_to doesn't hand the frame back
+                                self.frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(phys_frame) });
+                                MemoryError::from(error)
+                            })
+                    },
+                    None => Err(MemoryError::OutOfFrames),
+                };
+
+                if let Err(error) = result {
+                    // undo the pages mapped so far
+                    self.unmap_pages(Page::range(pages.start, pages.start + mapped as u64), mapper);
+                    return Err(error);
+                }
+                if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                    unsafe { set_user_accessible_parents(mapper, p) };
                 }
             }
 
             Ok(())
     }
 
-    /*
-    pub fn request_address_space(&mut self, mapper: &mut offsetpagetable) -> memoryregion {
+    // Unmaps whichever of the pages are mapped. Their frames are freed
+    // unless a forked address space still maps them. Huge pages have to be
+    // covered entirely, see split_huge_page_edges.
+    fn unmap_pages(&mut self, pages: PageRange<Size4KiB>, mapper: &mut OffsetPageTable) {
+        let mut p = pages.start;
+        while p < pages.end {
+            let huge = Page::<Size2MiB>::containing_address(p.start_address());
+            if let Some(entry) = unsafe { huge_page_entry(mapper, huge) } {
+                assert!(p + PAGES_PER_HUGE_PAGE <= pages.end, "unmapping part of a huge page");
+                // huge pages are kernel memory and never shared, their
+                // frames are freed as 4KiB frames
+                let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+                entry.set_unused();
+                x86_64::instructions::tlb::flush(p.start_address());
+                for frame in PhysFrame::range(frame, frame + PAGES_PER_HUGE_PAGE) {
+                    self.frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+                }
+                p += PAGES_PER_HUGE_PAGE;
+                continue;
+            }
+
+            if let Ok((frame, flush)) = mapper.unmap(p) {
+                flush.flush();
+                if self.frame_refs.release(frame) {
+                    self.frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+                }
+            }
+            p += 1;
+        }
+    }
+
+    // Replaces a 2MiB page with a table of 4KiB pages mapping the same
+    // frames
+    fn split_huge_page(&mut self, page: Page<Size2MiB>, mapper: &mut OffsetPageTable) -> Result<(), MemoryError> {
+        let entry = unsafe { huge_page_entry(mapper, page) }.ok_or(MemoryError::NotMapped)?;
+        let table_frame = *self.frame_allocator.allocate_frame().ok_or(MemoryError::PageTableAllocFailed)?;
+        let table = unsafe {
+            &mut *(phys_mem_offset() + table_frame.start_address().as_u64()).as_mut_ptr::<PageTable>()
+        };
+
+        let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+        for (index, small) in table.iter_mut().enumerate() {
+            small.set_addr(entry.addr() + index as u64 * Size4KiB::SIZE, flags);
+        }
+        let parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE);
+        entry.set_addr(table_frame.start_address(), parent);
+        x86_64::instructions::tlb::flush(page.start_address());
+        Ok(())
+    }
+
+    // Splits the huge pages at either end of the range that it only covers
+    // in part, so that every huge page left is entirely inside or outside
+    fn split_huge_page_edges(&mut self, pages: PageRange<Size4KiB>, mapper: &mut OffsetPageTable)
+        -> Result<(), MemoryError> {
+        if pages.start >= pages.end {
+            return Ok(());
+        }
+        for &p in &[pages.start, pages.end - 1] {
+            let huge = Page::<Size2MiB>::containing_address(p.start_address());
+            let first = Page::<Size4KiB>::containing_address(huge.start_address());
+            let covered = first >= pages.start && first + PAGES_PER_HUGE_PAGE <= pages.end;
+            if !covered && unsafe { huge_page_entry(mapper, huge) }.is_some() {
+                self.split_huge_page(huge, mapper)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Finds the lowest free range of size bytes in the arena of the page
+    // table at page_table. align must be a power of two.
+    pub fn find_free_range(&self, size: usize, align: usize, page_table: PhysAddr) -> Option<VirtAddr> {
+        let align = align.max(Size4KiB::SIZE as usize);
+        let size = align_up(size.max(1), Size4KiB::SIZE as usize) as u64;
+        let arena_end = self.arena_start.as_u64() + self.arena_size as u64;
+        let mut candidate = align_up(self.arena_start.as_u64() as usize, align) as u64;
+
+        // regions are sorted so each one either ends before the candidate,
+        // pushes it past itself or starts after the gap. MMIO regions are
+        // few and unsorted, one in the way starts the search over past it.
+        loop {
+            for region in self.used_memory_regions.iter().filter(|r| r.page_table == page_table) {
+                if region.end().as_u64() <= candidate {
+                    continue;
+                }
+                if region.start.as_u64() >= candidate + size {
+                    break;
+                }
+                candidate = align_up(region.end().as_u64() as usize, align) as u64;
+            }
+            let mmio = self.mmio_regions.iter().map(|mmio| &mmio.region).find(|region| {
+                region.page_table == page_table
+                    && region.start.as_u64() < candidate + size && candidate < region.end().as_u64()
+            });
+            match mmio {
+                Some(region) => candidate = align_up(region.end().as_u64() as usize, align) as u64,
+                None => break,
+            }
+        }
+
+        if candidate + size <= arena_end {
+            Some(VirtAddr::new(candidate))
+        } else {
+            None
+        }
+    }
+
+    // Maps size bytes at an address of the manager's choosing
+    pub fn request_address_space(&mut self, size: usize, align: usize, mapper: &mut OffsetPageTable) -> Result<MemoryRegion, MemoryError> {
+        let addr = self.find_free_range(size, align, region_table(self.arena_start, mapper))
+            .ok_or(MemoryError::OutOfAddressSpace)?;
+        self.request_address_space_at(addr, size, mapper)
+    }
 
+    // Maps kernel data, readable and writable but not executable
+    pub fn request_address_space_at(&mut self, addr: VirtAddr, size: usize, mapper: &mut OffsetPageTable) -> Result<MemoryRegion, MemoryError> {
+        self.request_address_space_with_protection_at(addr, size, Protection::READ | Protection::WRITE, mapper)
     }
 
-    */
+    pub fn request_address_space_with_protection_at(&mut self, addr: VirtAddr, size: usize, protection: Protection, mapper: &mut OffsetPageTable) -> Result<MemoryRegion, MemoryError> {
+        self.check_protection(protection)?;
+        Self::check_kernel_half(addr, size, protection)?;
+        let page_table = region_table(addr, mapper);
+        if self.overlaps(addr, size, page_table) {
+            return Err(MemoryError::Overlap);
+        }
 
-    pub fn request_address_space_at<M: Mapper<Size4KiB>>(&mut self, addr: VirtAddr, size: usize, mapper: &mut M) -> Result<MemoryRegion, MapToError<Size4KiB>> {
-        // FIXME: clone is most likely not necessary 
-        // FIXME: no error handling -- panics if memory is mapped over 
-        // again
-        // FIXME: size has no effect 
-        self.map(addr, size, mapper)?;
+        self.map(addr, size, protection.page_table_flags(), mapper)?;
         let r = MemoryRegion { 
             start: addr,
             size,
+            demand_paged: false,
+            page_table,
+            protection,
+        };
+        self.insert_region(r);
+        Ok(r)
+    }
+
+    // Like request_address_space_at but the pages can be accessed from
+    // ring 3. Code has to be made executable with protect.
+    pub fn request_user_address_space_at(&mut self, addr: VirtAddr, size: usize, mapper: &mut OffsetPageTable) -> Result<MemoryRegion, MemoryError> {
+        let protection = Protection::READ | Protection::WRITE | Protection::USER;
+        self.request_address_space_with_protection_at(addr, size, protection, mapper)
+    }
+
+    // mprotect: changes the protection of every page overlapping
+    // addr..addr + size. Regions that are only partly covered are split.
+    pub fn protect(&mut self, addr: VirtAddr, size: usize, protection: Protection, mapper: &mut OffsetPageTable)
+        -> Result<(), MemoryError> {
+        self.check_protection(protection)?;
+        Self::check_kernel_half(addr, size, protection)?;
+        let page_table = region_table(addr, mapper);
+        let pages = page_range(addr, size);
+        let (start, end) = (pages.start.start_address(), pages.end.start_address());
+        self.check_mapped(pages, page_table, mapper)?;
+        self.split_huge_page_edges(pages, mapper)?;
+
+        let flags = protection.page_table_flags();
+        let mut p = pages.start;
+        while p < pages.end {
+            let huge = Page::<Size2MiB>::containing_address(p.start_address());
+            if let Some(entry) = unsafe { huge_page_entry(mapper, huge) } {
+                entry.set_flags(flags | PageTableFlags::HUGE_PAGE);
+                x86_64::instructions::tlb::flush(p.start_address());
+                if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                    unsafe { set_user_accessible_parents(mapper, p) };
+                }
+                p += PAGES_PER_HUGE_PAGE;
+                continue;
+            }
+
+            let entry = match unsafe { page_entry(mapper, p) } {
+                Some(entry) if !entry.is_unused() => entry,
+                // an untouched demand paged page
+                _ => {
+                    p += 1;
+                    continue;
+                },
+            };
+
+            // a frame shared with another address space stays read only
+            // until a write gives this one its own copy
+            let mut page_flags = flags;
+            let shared = self.frame_refs.is_shared(PhysFrame::containing_address(entry.addr()));
+            if shared && page_flags.contains(PageTableFlags::WRITABLE) {
+                page_flags.remove(PageTableFlags::WRITABLE);
+                page_flags.insert(COPY_ON_WRITE);
+            }
+            entry.set_flags(page_flags);
+            x86_64::instructions::tlb::flush(p.start_address());
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                unsafe { set_user_accessible_parents(mapper, p) };
+            }
+            p += 1;
+        }
+
+        self.split_regions(start, end, page_table);
+        for region in self.used_memory_regions.iter_mut() {
+            if region.page_table == page_table && region.overlaps(start, end) {
+                region.protection = protection;
+            }
+        }
+        Ok(())
+    }
+
+    // Every page must belong to a region and be mapped unless the region
+    // is demand paged
+    fn check_mapped(&self, pages: PageRange<Size4KiB>, page_table: PhysAddr, mapper: &mut OffsetPageTable)
+        -> Result<(), MemoryError> {
+        for p in pages {
+            let page_start = p.start_address();
+            let page_end = page_start + Size4KiB::SIZE;
+            let region = self.used_memory_regions.iter()
+                .find(|region| region.page_table == page_table && region.overlaps(page_start, page_end));
+            match region {
+                Some(region) if region.demand_paged || is_mapped(mapper, p) => (),
+                _ => return Err(MemoryError::NotMapped),
+            }
+        }
+        Ok(())
+    }
+
+    // Splits the regions of page_table that straddle start or end so that
+    // each one lies either entirely inside or entirely outside the range
+    fn split_regions(&mut self, start: VirtAddr, end: VirtAddr, page_table: PhysAddr) {
+        let mut pieces = Vec::new();
+        self.used_memory_regions.retain(|region| {
+            let region_end = region.start + region.size;
+            let straddles = region.page_table == page_table && region.overlaps(start, end)
+                && (region.start < start || region_end > end);
+            if !straddles {
+                return true;
+            }
+
+            let (mut middle_start, mut middle_end) = (region.start, region_end);
+            if region.start < start {
+                pieces.push(MemoryRegion { size: (start - region.start) as usize, ..*region });
+                middle_start = start;
+            }
+            if region_end > end {
+                pieces.push(MemoryRegion { start: end, size: (region_end - end) as usize, ..*region });
+                middle_end = end;
+            }
+            pieces.push(MemoryRegion { start: middle_start, size: (middle_end - middle_start) as usize, ..*region });
+            false
+        });
+        for region in pieces {
+            self.insert_region(region);
+        }
+    }
+
+    // Reserves the range without mapping anything. Frames are allocated
+    // and zeroed by the page fault handler when a page is first touched.
+    pub fn reserve_address_space_at(&mut self, addr: VirtAddr, size: usize, mapper: &mut OffsetPageTable)
+        -> Result<MemoryRegion, MemoryError> {
+        let protection = Protection::READ | Protection::WRITE;
+        Self::check_kernel_half(addr, size, protection)?;
+        let page_table = region_table(addr, mapper);
+        if self.overlaps(addr, size, page_table) {
+            return Err(MemoryError::Overlap);
+        }
+
+        let r = MemoryRegion {
+            start: addr,
+            size,
+            demand_paged: true,
+            page_table,
+            protection,
         };
-        self.used_memory_regions.push(r.clone());
+        self.insert_region(r);
         Ok(r)
     }
 
-    pub fn relinquish_address_space<M: Mapper<Size4KiB>>(&mut self, addr: VirtAddr, size: usize,  mapper: &mut M) {
-        // FIXME: size has no effect
-        // FIXME: error handling
-        // FIXME: bug not allocating frames for single pages. 
-        // only allocating if pages are > 1 (i.e. size > 4kb)
-        let start_page: Page<Size4KiB> = Page::containing_address(addr);
-        let end_page = Page::containing_address(VirtAddr::new(addr.as_u64() + size as u64));
+    pub fn find_region(&self, addr: VirtAddr, mapper: &mut OffsetPageTable) -> Option<&MemoryRegion> {
+        let page_table = region_table(addr, mapper);
+        self.used_memory_regions.iter()
+            .find(|region| region.page_table == page_table && region.contains(addr))
+    }
 
-        let page_range = Page::range(start_page, end_page);
-        for p in page_range { 
-            mapper.unmap(p).expect("could not unmap page").1.flush();
+    // Frees a process address space along with the bookkeeping for every
+    // region that was mapped into it
+    pub fn destroy_address_space(&mut self, space: AddressSpace) {
+        let page_table = space.level_4_frame().start_address();
+        self.used_memory_regions.retain(|region| region.page_table != page_table);
+        space.destroy(&mut self.frame_allocator, &mut self.frame_refs);
+    }
+
+    // Copies an address space for fork. The regions mapped into it are
+    // mapped into the copy as well.
+    pub fn fork_address_space(&mut self, space: &AddressSpace) -> Option<AddressSpace> {
+        let child = space.fork(&mut self.frame_allocator, &mut self.frame_refs)?;
+
+        let parent_table = space.level_4_frame().start_address();
+        let child_table = child.level_4_frame().start_address();
+        let regions: Vec<MemoryRegion> = self.used_memory_regions.iter()
+            .filter(|region| region.page_table == parent_table)
+            .map(|&region| MemoryRegion { page_table: child_table, ..region })
+            .collect();
+        for region in regions {
+            self.insert_region(region);
         }
-        self.used_memory_regions.retain(|&region| { region.start != addr });
+        Some(child)
+    }
+
+    // Gives the faulting address space its own copy of a copy-on-write
+    // page. The last address space sharing a frame takes it over instead.
+    pub fn handle_write_fault(&mut self, addr: VirtAddr, mapper: &mut OffsetPageTable) -> bool {
+        let page: Page<Size4KiB> = Page::containing_address(addr);
+        let entry = match unsafe { page_entry(mapper, page) } {
+            Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+            _ => return false,
+        };
+
+        let old_frame = PhysFrame::containing_address(entry.addr());
+        let mut flags = entry.flags();
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(PageTableFlags::WRITABLE);
+
+        if self.frame_refs.is_shared(old_frame) {
+            let new_frame = match self.frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            unsafe {
+                let src = (phys_mem_offset() + old_frame.start_address().as_u64()).as_ptr::<u8>();
+                let dest = (phys_mem_offset() + new_frame.start_address().as_u64()).as_mut_ptr::<u8>();
+                core::ptr::copy_nonoverlapping(src, dest, Size4KiB::SIZE as usize);
+            }
+            self.frame_refs.release(old_frame);
+            entry.set_addr(new_frame.start_address(), flags);
+        } else {
+            entry.set_flags(flags);
+        }
+        x86_64::instructions::tlb::flush(page.start_address());
+        true
+    }
+
+    pub fn handle_page_fault(&mut self, addr: VirtAddr, mapper: &mut OffsetPageTable) -> bool {
+        let flags = match self.find_region(addr, mapper) {
+            Some(region) if region.demand_paged => region.protection.page_table_flags(),
+            _ => return false,
+        };
+
+        let page: Page<Size4KiB> = Page::containing_address(addr);
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        unsafe {
+            let frame_ptr = (phys_mem_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+            core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize);
+        }
+
+        let phys_frame = *frame;
+        match mapper.map_to(page, frame, flags, &mut self.frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                    unsafe { set_user_accessible_parents(mapper, page) };
+                }
+                true
+            },
+            Err(_) => {
+                // map_to doesn't hand the frame back
+                self.frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(phys_frame) });
+                false
+            },
+        }
+    }
+
+    // Unmaps every page overlapping addr..addr + size. The range may cover
+    // part of a region, in which case whatever is left of the region stays
+    // tracked.
+    pub fn relinquish_address_space(&mut self, addr: VirtAddr, size: usize,  mapper: &mut OffsetPageTable) 
+        -> Result<(), MemoryError> {
+        let page_table = region_table(addr, mapper);
+        let pages = page_range(addr, size);
+        let (start, end) = (pages.start.start_address(), pages.end.start_address());
+
+        // check the whole range before changing anything
+        self.check_mapped(pages, page_table, mapper)?;
+        self.split_huge_page_edges(pages, mapper)?;
+        self.unmap_pages(pages, mapper);
+
+        self.split_regions(start, end, page_table);
+        self.used_memory_regions.retain(|region| {
+            region.page_table != page_table || !region.overlaps(start, end)
+        });
+        Ok(())
+    }
+
+    // Unmaps the MMIO region starting at addr. Its frames stay out of the
+    // frame allocator.
+    pub fn iounmap(&mut self, addr: VirtAddr, mapper: &mut OffsetPageTable) -> Result<(), MemoryError> {
+        let page_table = region_table(addr, mapper);
+        let index = self.mmio_regions.iter()
+            .position(|mmio| mmio.region.page_table == page_table && mmio.region.start == addr)
+            .ok_or(MemoryError::NotMapped)?;
+        let region = self.mmio_regions.remove(index).region;
+        unmap_device_pages(page_range(region.start, region.size), mapper);
+        Ok(())
+    }
+
+    pub fn mmio_regions(&self) -> &[MmioRegion] {
+        &self.mmio_regions
     }
 }
 
+// Unmaps pages without giving their frames to anyone. The entries are
+// cleared directly since Mapper::unmap takes the PAT bit for a huge page.
+fn unmap_device_pages(pages: PageRange<Size4KiB>, mapper: &mut OffsetPageTable) {
+    for p in pages {
+        if let Some(entry) = unsafe { page_entry(mapper, p) } {
+            entry.set_unused();
+            x86_64::instructions::tlb::flush(p.start_address());
+        }
+    }
+}
 
-#[cfg(test)]
-use x86_64::structures::paging::{UnusedPhysFrame, PhysFrame};
+// Frame allocators that know which physical memory is theirs to hand out,
+// as opposed to device memory or memory reserved by the firmware
+pub trait FrameOwner {
+    fn owns(&self, frame: PhysFrame) -> bool;
+}
+
+impl<A> MemoryManager<A>
+    where A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + FrameOwner {
+    // Maps the physical range phys..phys + size at addr with the given
+    // caching, for device memory. RAM the frame allocator hands out is
+    // refused. Only for kernel memory like huge pages, forking would treat
+    // the frames as shared RAM.
+    pub fn ioremap(&mut self, phys: PhysAddr, size: usize, addr: VirtAddr, cache: CacheMode,
+                   mapper: &mut OffsetPageTable) -> Result<MmioRegion, MemoryError> {
+        if phys.as_u64() % Size4KiB::SIZE != addr.as_u64() % Size4KiB::SIZE {
+            return Err(MemoryError::Misaligned);
+        }
+        let protection = Protection::READ | Protection::WRITE;
+        Self::check_kernel_half(addr, size, protection)?;
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let frames = page_range(addr, size).count() as u64;
+        if PhysFrame::range(first_frame, first_frame + frames).any(|frame| self.frame_allocator.owns(frame)) {
+            return Err(MemoryError::UsableMemory);
+        }
+        let page_table = region_table(addr, mapper);
+        let pages = page_range(addr, size);
+        if self.overlaps(addr, size, page_table) || pages.clone().any(|p| is_mapped(mapper, p)) {
+            return Err(MemoryError::Overlap);
+        }
+
+        let cache_flags = cache.page_table_flags();
+        // map_to refuses the PAT bit as it's the huge page bit in other
+        // levels, it's set once the page is mapped
+        let flags = protection.page_table_flags() | (cache_flags - pat::PAT_4KIB);
+        for (i, p) in pages.enumerate() {
+            let frame = unsafe { UnusedPhysFrame::new(first_frame + i as u64) };
+            match mapper.map_to(p, frame, flags, &mut self.frame_allocator) {
+                Ok(flush) => flush.ignore(),
+                Err(error) => {
+                    unmap_device_pages(Page::range(pages.start, p), mapper);
+                    return Err(error.into());
+                },
+            }
+            if cache_flags.contains(pat::PAT_4KIB) {
+                let entry = unsafe { page_entry(mapper, p) }.expect("page was just mapped");
+                entry.set_flags(entry.flags() | pat::PAT_4KIB);
+            }
+            x86_64::instructions::tlb::flush(p.start_address());
+        }
+
+        let mmio = MmioRegion {
+            region: MemoryRegion {
+                start: addr,
+                size,
+                demand_paged: false,
+                page_table,
+                protection,
+            },
+            phys,
+            cache,
+        };
+        self.mmio_regions.push(mmio);
+        Ok(mmio)
+    }
+
+}
+
+impl<A> MemoryManager<A>
+    where A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + FrameAllocator<Size2MiB> {
+    // Like request_address_space_with_protection_at but 2MiB aligned parts
+    // of the range are mapped with 2MiB pages where contiguous frames are
+    // available. Only for kernel memory: forking and tearing down address
+    // spaces deal in 4KiB pages.
+    pub fn request_large_address_space_at(&mut self, addr: VirtAddr, size: usize, protection: Protection,
+                                          mapper: &mut OffsetPageTable) -> Result<MemoryRegion, MemoryError> {
+        self.check_protection(protection)?;
+        if protection.contains(Protection::USER) {
+            return Err(MemoryError::Unsupported);
+        }
+        Self::check_kernel_half(addr, size, protection)?;
+        let page_table = region_table(addr, mapper);
+        let pages = page_range(addr, size);
+        if self.overlaps(addr, size, page_table) || pages.clone().any(|p| is_mapped(mapper, p)) {
+            return Err(MemoryError::Overlap);
+        }
+
+        let flags = protection.page_table_flags();
+        let mut p = pages.start;
+        while p < pages.end {
+            let result = if self.map_huge_page(p, pages.end, flags, mapper) {
+                p += PAGES_PER_HUGE_PAGE;
+                Ok(())
+            } else {
+                let result = self.map(p.start_address(), Size4KiB::SIZE as usize, flags, mapper);
+                p += 1;
+                result
+            };
+            if let Err(error) = result {
+                self.unmap_pages(Page::range(pages.start, p - 1), mapper);
+                return Err(error);
+            }
+        }
+
+        let r = MemoryRegion {
+            start: addr,
+            size,
+            demand_paged: false,
+            page_table,
+            protection,
+        };
+        self.insert_region(r);
+        Ok(r)
+    }
+
+    // Maps a 2MiB page at p if it's aligned, ends before end and a 2MiB
+    // frame is free. Returns false if nothing was mapped.
+    fn map_huge_page(&mut self, p: Page<Size4KiB>, end: Page<Size4KiB>, flags: PageTableFlags,
+                     mapper: &mut OffsetPageTable) -> bool {
+        if !p.start_address().is_aligned(Size2MiB::SIZE) || p + PAGES_PER_HUGE_PAGE > end {
+            return false;
+        }
+        let frame: UnusedPhysFrame<Size2MiB> = match FrameAllocator::<Size2MiB>::allocate_frame(&mut self.frame_allocator) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let phys_frame = *frame;
+        let page = Page::<Size2MiB>::containing_address(p.start_address());
+        match mapper.map_to(page, frame, flags, &mut self.frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(_) => {
+                // map_to doesn't hand the frame back
+                let first = PhysFrame::<Size4KiB>::containing_address(phys_frame.start_address());
+                for frame in PhysFrame::range(first, first + PAGES_PER_HUGE_PAGE) {
+                    self.frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+                }
+                false
+            },
+        }
+    }
+}
 
 #[cfg(test)]
 struct DummyAlloc {
@@ -142,6 +1139,12 @@ unsafe impl FrameAllocator<Size4KiB> for DummyAlloc {
     }
 }
 
+#[cfg(test)]
+impl FrameDeallocator<Size4KiB> for DummyAlloc {
+    fn deallocate_frame(&mut self, _frame: UnusedPhysFrame) {
+    }
+}
+
 #[test_case]
 fn test_memory_manager() {
     // TODO: test translation when page is mapped/unmapped
@@ -157,20 +1160,353 @@ fn test_memory_manager() {
     };
 
     use x86_64::structures::paging::mapper::MapperAllSizes;
-    memory_manager.request_address_space_at(test_addr, 5 * 1024, &mut mapper);
+    memory_manager.request_address_space_at(test_addr, 5 * 1024, &mut mapper)
+        .expect("could not request address space");
     // dbg_println!("Requested address space from memory manager. Page should be mapped");
     // dbg_println!("{:?} -> {:?}", test_addr, mapper.translate(test_addr));
     // dbg_println!("Memory regions: {:?}", memory_manager.get_used_regions());
     assert!(memory_manager.get_used_regions().len() == 1);
     //dbg_println!("");
     //dbg_println!("Relinquishing address space");
-    memory_manager.relinquish_address_space(test_addr, 5 * 1024, &mut mapper);
+    memory_manager.relinquish_address_space(test_addr, 5 * 1024, &mut mapper)
+        .expect("could not relinquish address space");
     assert!(memory_manager.get_used_regions().len() == 0);
     //dbg_println!("Memory regions: {:?}", memory_manager.get_used_regions());
     //dbg_println!("");
-    memory_manager.request_address_space_at(test_addr, 1024, &mut mapper);
+    memory_manager.request_address_space_at(test_addr, 1024, &mut mapper)
+        .expect("could not request address space");
     assert!(memory_manager.get_used_regions().len() == 1);
     //dbg_println!("{:?} -> {:?}", test_addr, mapper.translate(test_addr));
     //dbg_println!("Memory regions: {:?}", memory_manager.get_used_regions());
+    memory_manager.relinquish_address_space(test_addr, 1024, &mut mapper)
+        .expect("could not relinquish address space");
+
+}
+
+#[test_case]
+fn test_overlap_and_split() {
+    use x86_64::structures::paging::mapper::MapperAllSizes;
+
+    let test_addr = VirtAddr::new(0x0e80000000);
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+
+    manager.request_address_space_at(test_addr, 4 * 4096, &mut mapper)
+        .expect("could not request address space");
+    assert_eq!(manager.request_address_space_at(test_addr + 3 * 4096u64, 4096, &mut mapper).err(),
+               Some(MemoryError::Overlap));
+    assert_eq!(manager.reserve_address_space_at(test_addr - 4096u64, 2 * 4096, &mut mapper).err(),
+               Some(MemoryError::Overlap));
+
+    // punching a hole leaves a region on either side
+    manager.relinquish_address_space(test_addr + 4096u64, 2 * 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(mapper.translate_addr(test_addr).is_some());
+    assert!(mapper.translate_addr(test_addr + 4096u64).is_none());
+    assert!(mapper.translate_addr(test_addr + 3 * 4096u64).is_some());
+    assert_eq!(manager.find_region(test_addr, &mut mapper).map(|r| r.size), Some(4096));
+    assert_eq!(manager.find_region(test_addr + 3 * 4096u64, &mut mapper).map(|r| r.size), Some(4096));
+
+    assert_eq!(manager.relinquish_address_space(test_addr, 2 * 4096, &mut mapper),
+               Err(MemoryError::NotMapped));
+    manager.relinquish_address_space(test_addr, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    manager.relinquish_address_space(test_addr + 3 * 4096u64, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(manager.find_region(test_addr + 3 * 4096u64, &mut mapper).is_none());
+}
+
+#[test_case]
+fn test_demand_paging() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+    use x86_64::instructions::interrupts;
+    use crate::memory::kernel_stack::KernelStack;
+    use crate::task::TASK_MANAGER;
+
+    let test_addr = VirtAddr::new(0x0e00000000);
+    {
+        let mut guard = MEMORY_MANAGER.lock();
+        let manager = guard.as_mut().expect("memory manager not installed");
+        let mut mapper = unsafe { active_page_table() };
+        manager.reserve_address_space_at(test_addr, 2 * 4096, &mut mapper)
+            .expect("could not reserve address space");
+    }
+
+    // touching the region faults in zeroed pages
+    let ptr = test_addr.as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+
+    // a kernel fault while another proc holds the manager waits for it
+    let locked = Arc::new(AtomicBool::new(false));
+    let holder = {
+        let locked = locked.clone();
+        let stack = KernelStack::new().expect("could not allocate kernel stack");
+        interrupts::without_interrupts(|| {
+            TASK_MANAGER.write().spawn_closure(stack, move || {
+                let _guard = MEMORY_MANAGER.lock();
+                locked.store(true, Ordering::SeqCst);
+                crate::scheduler::sleep_ms(20);
+                0
+            }).expect("could not spawn").read().id
+        })
+    };
+    while !locked.load(Ordering::SeqCst) {
+        crate::scheduler::yield_now();
+    }
+    unsafe {
+        assert_eq!(ptr.add(512).read_volatile(), 0);
+    }
+    assert_eq!(crate::task::join(holder), Ok(0));
+
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+    manager.relinquish_address_space(test_addr, 2 * 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(manager.find_region(test_addr, &mut mapper).is_none());
+
+    // kernel regions requested through a process' page table belong to
+    // the kernel's, where faults from any address space find them
+    let space = AddressSpace::new(&mut manager.frame_allocator).expect("could not create address space");
+    let mut space_mapper = unsafe { space.page_table() };
+    manager.reserve_address_space_at(test_addr, 4096, &mut space_mapper)
+        .expect("could not reserve address space");
+    assert!(manager.find_region(test_addr, &mut mapper).is_some());
+    manager.destroy_address_space(space);
+    assert!(manager.find_region(test_addr, &mut mapper).is_some());
+    manager.relinquish_address_space(test_addr, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+}
+
+#[test_case]
+fn test_find_free_range() {
+    let mut manager = MemoryManager::new(DummyAlloc::new());
+    let mut mapper = unsafe { active_page_table() };
+    let page_table = page_table_address(&mut mapper);
+
+    let base = VirtAddr::new(DEFAULT_ARENA_START);
+    manager.set_arena(base, 16 * 4096);
+    manager.reserve_address_space_at(base + 3 * 4096u64, 4096, &mut mapper)
+        .expect("could not reserve address space");
+    manager.reserve_address_space_at(base, 4096, &mut mapper)
+        .expect("could not reserve address space");
+
+    // the gap between the regions only fits two pages
+    assert_eq!(manager.find_free_range(2 * 4096, 4096, page_table), Some(base + 4096u64));
+    assert_eq!(manager.find_free_range(3 * 4096, 4096, page_table), Some(base + 4 * 4096u64));
+    assert_eq!(manager.find_free_range(4096, 4 * 4096, page_table), Some(base + 4 * 4096u64));
+    assert_eq!(manager.find_free_range(12 * 4096, 4096, page_table), Some(base + 4 * 4096u64));
+    assert_eq!(manager.find_free_range(13 * 4096, 4096, page_table), None);
+}
+
+#[test_case]
+fn test_request_address_space() {
+    use x86_64::structures::paging::mapper::MapperAllSizes;
+
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+
+    let first = manager.request_address_space(3 * 4096, 4096, &mut mapper)
+        .expect("could not allocate address space");
+    let second = manager.request_address_space(4096, 0x20_0000, &mut mapper)
+        .expect("could not allocate address space");
+    assert!(first.end() <= second.start);
+    assert!(second.start.is_aligned(0x20_0000u64));
+
+    unsafe {
+        let ptr = (first.start + 2 * 4096u64).as_mut_ptr::<u64>();
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+
+    // kernel memory can't go where other address spaces wouldn't see it
+    let user_addr = (1..256u64)
+        .map(|index| VirtAddr::new(index << 39))
+        .find(|&addr| super::address_space::is_user_address(addr))
+        .expect("no free level 4 entry");
+    assert_eq!(manager.request_address_space_at(user_addr, 4096, &mut mapper).err(),
+               Some(MemoryError::Unsupported));
+    // nor user memory where every address space would
+    let kernel_addr = VirtAddr::new(0x0d00000000);
+    assert_eq!(manager.request_user_address_space_at(kernel_addr, 4096, &mut mapper).err(),
+               Some(MemoryError::Unsupported));
+
+    // the arena is kernel memory that every address space shares
+    let space = AddressSpace::new(&mut manager.frame_allocator)
+        .expect("could not create address space");
+    let shared = unsafe { space.page_table() }.translate_addr(first.start);
+    assert_eq!(shared, mapper.translate_addr(first.start));
+    manager.destroy_address_space(space);
+
+    manager.relinquish_address_space(second.start, second.size, &mut mapper)
+        .expect("could not relinquish address space");
+    manager.relinquish_address_space(first.start, first.size, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(manager.find_region(first.start, &mut mapper).is_none());
+}
+
+#[test_case]
+fn test_protect() {
+    let test_addr = VirtAddr::new(0x0d80000000);
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+    let flags = |mapper: &mut OffsetPageTable, addr: VirtAddr| unsafe {
+        page_entry(mapper, Page::containing_address(addr)).map(|entry| entry.flags())
+    };
+
+    let region = manager.request_address_space_at(test_addr, 3 * 4096, &mut mapper)
+        .expect("could not request address space");
+    assert_eq!(region.protection, Protection::READ | Protection::WRITE);
+    let page_flags = flags(&mut mapper, test_addr).expect("page not mapped");
+    assert!(page_flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+    // making the middle page read only splits the region
+    manager.protect(test_addr + 4096u64, 4096, Protection::READ, &mut mapper)
+        .expect("could not protect region");
+    assert!(!flags(&mut mapper, test_addr + 4096u64).unwrap().contains(PageTableFlags::WRITABLE));
+    assert!(flags(&mut mapper, test_addr + 2 * 4096u64).unwrap().contains(PageTableFlags::WRITABLE));
+    let middle = manager.find_region(test_addr + 4096u64, &mut mapper).expect("region lost");
+    assert_eq!((middle.start, middle.size), (test_addr + 4096u64, 4096));
+    assert_eq!(middle.protection, Protection::READ);
+
+    manager.protect(test_addr, 3 * 4096, Protection::READ | Protection::EXECUTE, &mut mapper)
+        .expect("could not protect region");
+    assert!(!flags(&mut mapper, test_addr).unwrap().contains(PageTableFlags::NO_EXECUTE));
+    assert_eq!(manager.protect(test_addr, 4 * 4096, Protection::READ, &mut mapper),
+               Err(MemoryError::NotMapped));
+
+    manager.relinquish_address_space(test_addr, 3 * 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(manager.find_region(test_addr, &mut mapper).is_none());
+}
+
+#[test_case]
+fn test_large_pages() {
+    use super::MappingSize;
+
+    let test_addr = VirtAddr::new(0x0c00000000);
+    let size = Size2MiB::SIZE as usize + 2 * 4096;
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+    let mapping = |mapper: &mut OffsetPageTable, addr: VirtAddr| unsafe {
+        let level_4 = PhysFrame::containing_address(page_table_address(mapper));
+        walk_page_table(level_4, addr, phys_mem_offset()).map(|translation| translation.size)
+    };
+
+    assert_eq!(manager.request_large_address_space_at(test_addr, size, Protection::READ | Protection::USER, &mut mapper)
+                   .err(), Some(MemoryError::Unsupported));
+    manager.request_large_address_space_at(test_addr, size, Protection::READ | Protection::WRITE, &mut mapper)
+        .expect("could not request address space");
+    // the aligned 2MiB is one huge page, the tail 4KiB pages
+    assert_eq!(mapping(&mut mapper, test_addr), Some(MappingSize::Size2MiB));
+    assert_eq!(mapping(&mut mapper, test_addr + Size2MiB::SIZE), Some(MappingSize::Size4KiB));
+    let last = (test_addr + size - 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        *test_addr.as_mut_ptr::<u64>() = 0xdead_beef;
+        *last = 0xcafe;
+        assert_eq!(*test_addr.as_ptr::<u64>(), 0xdead_beef);
+        assert_eq!(*last, 0xcafe);
+    }
+
+    // giving back part of the huge page splits it
+    manager.relinquish_address_space(test_addr + 4096u64, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert_eq!(mapping(&mut mapper, test_addr), Some(MappingSize::Size4KiB));
+    assert_eq!(mapping(&mut mapper, test_addr + 4096u64), None);
+    assert_eq!(unsafe { *test_addr.as_ptr::<u64>() }, 0xdead_beef);
+
+    manager.relinquish_address_space(test_addr, 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    manager.relinquish_address_space(test_addr + 2 * 4096u64, size - 2 * 4096, &mut mapper)
+        .expect("could not relinquish address space");
+    assert!(manager.find_region(test_addr + Size2MiB::SIZE, &mut mapper).is_none());
+}
+
+#[test_case]
+fn test_ioremap() {
+    // the VGA text buffer is device memory outside the frame allocator
+    let phys = PhysAddr::new(0xb8000);
+    let test_addr = VirtAddr::new(0x0b00000000);
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+
+    assert_eq!(manager.ioremap(phys, 4096, test_addr + 8u64, CacheMode::Uncached, &mut mapper).err(),
+               Some(MemoryError::Misaligned));
+    // RAM isn't device memory, whether it's in use or not
+    let heap = unsafe { super::translate_addr(VirtAddr::new(super::allocator::HEAP_START as u64), phys_mem_offset()) }
+        .expect("heap not mapped");
+    assert_eq!(manager.ioremap(heap, 4096, test_addr, CacheMode::Uncached, &mut mapper).err(),
+               Some(MemoryError::UsableMemory));
+    let frame: UnusedPhysFrame = manager.frame_allocator.allocate_frame().expect("out of frames");
+    let free = frame.start_address();
+    manager.frame_allocator.deallocate_frame(frame);
+    assert_eq!(manager.ioremap(free, 4096, test_addr, CacheMode::Uncached, &mut mapper).err(),
+               Some(MemoryError::UsableMemory));
+    // device memory is kernel memory
+    let user_addr = (1..256u64)
+        .map(|index| VirtAddr::new(index << 39))
+        .find(|&addr| super::address_space::is_user_address(addr))
+        .expect("no free level 4 entry");
+    assert_eq!(manager.ioremap(phys, 4096, user_addr, CacheMode::Uncached, &mut mapper).err(),
+               Some(MemoryError::Unsupported));
+    let mmio = manager.ioremap(phys, 2 * 4096, test_addr, CacheMode::WriteCombining, &mut mapper)
+        .expect("could not map device memory");
+    assert_eq!(mmio.region.start, test_addr);
+    assert_eq!(unsafe { super::translate_addr(test_addr + 4096u64 + 2u64, phys_mem_offset()) },
+               Some(phys + 4096u64 + 2u64));
+    let flags = unsafe { page_entry(&mut mapper, Page::containing_address(test_addr)) }.unwrap().flags();
+    if pat::write_combining_enabled() {
+        assert!(flags.contains(pat::PAT_4KIB));
+    } else {
+        assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+    }
+    let cell = unsafe { core::ptr::read_volatile(test_addr.as_ptr::<u16>()) };
+    let direct = unsafe { core::ptr::read_volatile((phys_mem_offset() + phys.as_u64()).as_ptr::<u16>()) };
+    assert_eq!(cell, direct);
+
+    // MMIO regions aren't memory regions, they can't be requested over or
+    // given back like them
+    assert_eq!(manager.request_address_space_at(test_addr + 4096u64, 4096, &mut mapper).err(),
+               Some(MemoryError::Overlap));
+    assert_eq!(manager.relinquish_address_space(test_addr, 4096, &mut mapper),
+               Err(MemoryError::NotMapped));
+    assert!(manager.find_region(test_addr, &mut mapper).is_none());
+
+    manager.iounmap(test_addr, &mut mapper).expect("could not unmap device memory");
+    assert!(!is_mapped(&mut mapper, Page::containing_address(test_addr + 4096u64)));
+    assert!(manager.mmio_regions().is_empty());
+    assert_eq!(manager.iounmap(test_addr, &mut mapper), Err(MemoryError::NotMapped));
+}
+
+#[test_case]
+#[cfg(debug_assertions)]
+fn test_enforce_wx() {
+    let test_addr = VirtAddr::new(0x0d00000000);
+    let mut mapper = unsafe { active_page_table() };
+    let mut manager = MemoryManager::new(DummyAlloc::new());
+
+    // on by default
+    let rwx = Protection::READ | Protection::WRITE | Protection::EXECUTE;
+    assert_eq!(manager.request_address_space_with_protection_at(test_addr, 4096, rwx, &mut mapper).err(),
+               Some(MemoryError::WritableAndExecutable));
+    manager.reserve_address_space_at(test_addr, 4096, &mut mapper)
+        .expect("could not reserve address space");
+    assert_eq!(manager.protect(test_addr, 4096, rwx, &mut mapper),
+               Err(MemoryError::WritableAndExecutable));
+    manager.protect(test_addr, 4096, Protection::READ | Protection::EXECUTE, &mut mapper)
+        .expect("could not protect region");
 
+    manager.set_enforce_wx(false);
+    manager.protect(test_addr, 4096, rwx, &mut mapper)
+        .expect("could not protect region");
 }
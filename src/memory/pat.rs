@@ -0,0 +1,60 @@
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const IA32_PAT: u32 = 0x277;
+
+// Memory types a PAT entry can select
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+// Uncacheable unless an MTRR says write-combining
+const UNCACHED_MINUS: u64 = 0x07;
+
+// Entries 0-3 keep their power-on types, so the PWT and PCD bits mean what
+// they mean without PAT. Entry 4 becomes write-combining: nothing sets the
+// PAT bit before init, so no mapping changes its type.
+const LAYOUT: [u64; 8] = [
+    WRITE_BACK, WRITE_THROUGH, UNCACHED_MINUS, UNCACHEABLE,
+    WRITE_COMBINING, WRITE_THROUGH, UNCACHED_MINUS, UNCACHEABLE,
+];
+
+// Bit 7 selects the upper half of the PAT in 4KiB page table entries. In
+// the levels above it is the huge page bit instead.
+pub const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static WRITE_COMBINING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn supported() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 16) != 0
+}
+
+// Programs the PAT so that write-combining can be selected. Without PAT
+// support write-combining mappings fall back to uncacheable.
+pub fn init() {
+    if !supported() {
+        return;
+    }
+    let value = LAYOUT.iter().enumerate()
+        .fold(0, |value, (index, &memory_type)| value | memory_type << (8 * index));
+    unsafe {
+        Msr::new(IA32_PAT).write(value);
+    }
+    WRITE_COMBINING_ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn write_combining_enabled() -> bool {
+    WRITE_COMBINING_ENABLED.load(Ordering::SeqCst)
+}
+
+#[test_case]
+fn test_pat_layout() {
+    let pat = unsafe { Msr::new(IA32_PAT).read() };
+    if write_combining_enabled() {
+        assert_eq!((pat >> 32) & 0xff, WRITE_COMBINING);
+    }
+    // the entries selected without the PAT bit are the power-on ones
+    assert_eq!(pat & 0xffff_ffff, 0x0007_0406);
+}
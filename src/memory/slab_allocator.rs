@@ -0,0 +1,316 @@
+use super::allocator::{Heap, HeapStats, grow_heap, reserve_frame};
+use super::list_allocator::ListHeap;
+use alloc::alloc::{Layout, AllocErr};
+use core::ptr::{self, NonNull};
+use core::mem::size_of;
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB, UnusedPhysFrame};
+use x86_64::PhysAddr;
+
+use super::paging::MEMORY_MANAGER;
+use super::phys_mem_offset;
+
+const PAGE_SIZE: usize = 4096;
+// Object sizes of the caches, 8 << index
+const SIZE_CLASSES: usize = 8;
+const MAX_SLAB_SIZE: usize = 8 << (SIZE_CLASSES - 1);
+
+// Every slab page starts with this header, followed by objects of its
+// cache's size
+struct SlabPage {
+    prev: *mut SlabPage,
+    next: *mut SlabPage,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// Pages holding objects of one size. Only pages with a free object are
+// linked into the list, full pages are picked up again once an object in
+// them is freed.
+struct SlabCache {
+    object_size: usize,
+    partial: *mut SlabPage,
+    pages: usize,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            object_size,
+            partial: ptr::null_mut(),
+            pages: 0,
+        }
+    }
+
+    fn first_object(&self) -> usize {
+        super::allocator::align_up(size_of::<SlabPage>(), self.object_size)
+    }
+
+    unsafe fn allocate(&mut self) -> Option<NonNull<u8>> {
+        if self.partial.is_null() {
+            let page = allocate_page()?;
+            self.add_page(page);
+        }
+
+        let page = &mut *self.partial;
+        let object = page.free;
+        page.free = (*object).next;
+        page.in_use += 1;
+        if page.free.is_null() {
+            self.unlink(page);
+        }
+        NonNull::new(object as *mut u8)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let page = &mut *((ptr.as_ptr() as usize & !(PAGE_SIZE - 1)) as *mut SlabPage);
+        let was_full = page.free.is_null();
+        let object = ptr.as_ptr() as *mut FreeObject;
+        (*object).next = page.free;
+        page.free = object;
+        page.in_use -= 1;
+
+        if was_full {
+            self.link(page);
+        }
+        // empty pages go back to the frame allocator unless it's busy
+        if page.in_use == 0 {
+            self.unlink(page);
+            if free_page(page as *mut SlabPage as usize) {
+                self.pages -= 1;
+            } else {
+                self.link(page);
+            }
+        }
+    }
+
+    // Carves a fresh page into objects and links it in
+    unsafe fn add_page(&mut self, addr: usize) {
+        let page = addr as *mut SlabPage;
+        let mut free = ptr::null_mut();
+        let mut offset = PAGE_SIZE - self.object_size;
+        while offset >= self.first_object() {
+            let object = (addr + offset) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+            offset -= self.object_size;
+        }
+        page.write(SlabPage {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        self.link(&mut *page);
+        self.pages += 1;
+    }
+
+    unsafe fn link(&mut self, page: &mut SlabPage) {
+        page.prev = ptr::null_mut();
+        page.next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = page;
+        }
+        self.partial = page;
+    }
+
+    unsafe fn unlink(&mut self, page: &mut SlabPage) {
+        if page.prev.is_null() {
+            self.partial = page.next;
+        } else {
+            (*page.prev).next = page.next;
+        }
+        if !page.next.is_null() {
+            (*page.next).prev = page.prev;
+        }
+        page.prev = ptr::null_mut();
+        page.next = ptr::null_mut();
+    }
+}
+
+// Slab pages are frames used through the physical memory mapping so they
+// don't need to be mapped. While the memory manager is busy they come from
+// the heap's frame reserve, and failing that the allocation falls back to
+// the list heap.
+fn allocate_page() -> Option<usize> {
+    let frame: UnusedPhysFrame<Size4KiB> = match MEMORY_MANAGER.try_lock() {
+        Some(mut guard) => guard.as_mut()?.frame_allocator.allocate_frame()?,
+        None => reserve_frame()?,
+    };
+    Some((phys_mem_offset() + frame.start_address().as_u64()).as_u64() as usize)
+}
+
+// Returns false if the page couldn't be freed right now
+fn free_page(addr: usize) -> bool {
+    let mut guard = match MEMORY_MANAGER.try_lock() {
+        Some(guard) => guard,
+        None => return false,
+    };
+    let manager = match guard.as_mut() {
+        Some(manager) => manager,
+        None => return false,
+    };
+    let phys = PhysAddr::new(addr as u64 - phys_mem_offset().as_u64());
+    unsafe {
+        manager.frame_allocator.deallocate_frame(UnusedPhysFrame::new(PhysFrame::containing_address(phys)));
+    }
+    true
+}
+
+// Size class index for layout, None if it's too big for the caches
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align())
+        .next_power_of_two()
+        .max(size_of::<FreeObject>());
+    if size > MAX_SLAB_SIZE {
+        return None;
+    }
+    Some(size.trailing_zeros() as usize - 3)
+}
+
+// Power of two slab caches for small objects, with the list heap taking
+// anything larger
+pub struct SlabHeap {
+    caches: [SlabCache; SIZE_CLASSES],
+    list_heap: ListHeap,
+    // Only the kernel heap grows, its top is where grow_heap maps pages
+    growable: bool,
+}
+
+// The raw page pointers are only used under the heap's lock
+unsafe impl Send for SlabHeap {}
+
+impl SlabHeap {
+    pub const fn empty() -> SlabHeap {
+        SlabHeap {
+            caches: [
+                SlabCache::new(8), SlabCache::new(16), SlabCache::new(32), SlabCache::new(64),
+                SlabCache::new(128), SlabCache::new(256), SlabCache::new(512), SlabCache::new(1024),
+            ],
+            list_heap: ListHeap::empty(),
+            growable: false,
+        }
+    }
+
+    pub fn list_heap(&mut self) -> &mut ListHeap {
+        &mut self.list_heap
+    }
+
+    // Number of pages held by each size class
+    pub fn slab_pages(&self) -> [usize; SIZE_CLASSES] {
+        let mut pages = [0; SIZE_CLASSES];
+        for (count, cache) in pages.iter_mut().zip(self.caches.iter()) {
+            *count = cache.pages;
+        }
+        pages
+    }
+
+    // Lets the heap grow up to max_size. Only for the heap registered with
+    // the memory manager as the kernel heap.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.list_heap.set_max_size(max_size);
+        self.growable = true;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.list_heap.stats()
+    }
+
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.list_heap.contains(ptr.as_ptr() as usize)
+    }
+
+    fn allocate_from_list(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        self.list_heap.allocate(layout).or_else(|error| {
+            if self.growable && grow_heap(&mut self.list_heap, layout) {
+                self.list_heap.allocate(layout)
+            } else {
+                self.list_heap.grow_failed();
+                Err(error)
+            }
+        })
+    }
+}
+
+impl Heap for SlabHeap {
+    fn init(&mut self, heap_bottom: usize, heap_size: usize) {
+        self.list_heap.init(heap_bottom, heap_size);
+    }
+
+    fn new(heap_bottom: usize, heap_size: usize) -> SlabHeap {
+        let mut heap = SlabHeap::empty();
+        heap.init(heap_bottom, heap_size);
+        heap
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        // a checked list heap gets everything so it can check everything
+        if self.list_heap.checked() {
+            return self.allocate_from_list(layout);
+        }
+        if let Some(class) = size_class(&layout) {
+            if let Some(ptr) = unsafe { self.caches[class].allocate() } {
+                return Ok(ptr);
+            }
+        }
+        self.allocate_from_list(layout)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if self.owns(ptr) {
+            self.list_heap.deallocate(ptr, layout);
+        } else {
+            let class = size_class(&layout).expect("slab object with a large layout");
+            self.caches[class].deallocate(ptr);
+        }
+    }
+}
+
+#[test_case]
+fn test_slab_heap() {
+    use alloc::vec::Vec;
+    use super::allocator::LockedHeap;
+    use core::alloc::GlobalAlloc;
+
+    // the list heap part lives in a buffer on the global heap
+    let mut buffer: Vec<u64> = Vec::with_capacity(4096);
+    let heap: LockedHeap<SlabHeap> = unsafe {
+        LockedHeap::new(buffer.as_mut_ptr() as usize, 4096 * size_of::<u64>())
+    };
+    let mut objects: Vec<*mut u8> = Vec::with_capacity(200);
+    let used = MEMORY_MANAGER.lock().as_ref().unwrap().frame_allocator.used_frames();
+
+    // small objects come from slab pages, outside the list heap
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    for _ in 0..200 {
+        objects.push(unsafe { heap.alloc(layout) });
+    }
+    assert!(objects.iter().all(|&ptr| !ptr.is_null() && ptr as usize % 32 == 0));
+    assert!(!heap.lock().owns(NonNull::new(objects[0]).unwrap()));
+    let pages = heap.lock().slab_pages()[size_class(&layout).unwrap()];
+    assert_eq!(pages, 2);
+
+    // large ones from the list heap
+    let large = Layout::from_size_align(4000, 8).unwrap();
+    let ptr = unsafe { heap.alloc(large) };
+    assert!(heap.lock().owns(NonNull::new(ptr).unwrap()));
+
+    // only the kernel heap grows, this one sits in the middle of it
+    let too_large = Layout::from_size_align(2 * 4096 * size_of::<u64>(), 8).unwrap();
+    assert!(unsafe { heap.alloc(too_large) }.is_null());
+    assert_eq!(heap.lock().stats().grow_count, 0);
+
+    unsafe {
+        heap.dealloc(ptr, large);
+        for &object in objects.iter() {
+            heap.dealloc(object, layout);
+        }
+    }
+    // empty slab pages go back to the frame allocator
+    assert_eq!(heap.lock().slab_pages()[size_class(&layout).unwrap()], 0);
+    assert_eq!(MEMORY_MANAGER.lock().as_ref().unwrap().frame_allocator.used_frames(), used);
+}
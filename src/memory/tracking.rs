@@ -0,0 +1,227 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+// Live allocations recorded at once. Allocations beyond that are only
+// counted.
+const MAX_RECORDS: usize = 1024;
+// Size classes of the histogram: up to 8 bytes, up to 16, ... up to 4KiB
+// and everything larger
+pub const SIZE_CLASSES: usize = 11;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    // What the allocation was made for, see set_tag. It stands in for the
+    // caller, which isn't recorded: alloc is reached through the alloc
+    // crate's shims and without frame pointers there is no reliable way
+    // to find the return address past them.
+    pub tag: &'static str,
+    // Scope that was innermost when the allocation was made
+    generation: usize,
+}
+
+impl AllocationRecord {
+    const EMPTY: AllocationRecord = AllocationRecord {
+        addr: 0,
+        size: 0,
+        align: 0,
+        tag: "",
+        generation: 0,
+    };
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    // Allocations made while tracking
+    pub allocations: usize,
+    // Tracked allocations not freed yet
+    pub live: usize,
+    pub live_bytes: usize,
+}
+
+struct Tracker {
+    records: [AllocationRecord; MAX_RECORDS],
+    histogram: [SizeClassStats; SIZE_CLASSES],
+    // Allocations that didn't fit in records
+    dropped: usize,
+    generation: usize,
+    tag: &'static str,
+}
+
+impl Tracker {
+    const fn new() -> Tracker {
+        Tracker {
+            records: [AllocationRecord::EMPTY; MAX_RECORDS],
+            histogram: [SizeClassStats { allocations: 0, live: 0, live_bytes: 0 }; SIZE_CLASSES],
+            dropped: 0,
+            generation: 0,
+            tag: "",
+        }
+    }
+
+    // Returns false if the table is full
+    fn record(&mut self, addr: usize, layout: Layout) -> bool {
+        let class = &mut self.histogram[size_class(layout.size())];
+        class.allocations += 1;
+        match self.records.iter_mut().find(|record| record.addr == 0) {
+            Some(record) => {
+                *record = AllocationRecord {
+                    addr,
+                    size: layout.size(),
+                    align: layout.align(),
+                    tag: self.tag,
+                    generation: self.generation,
+                };
+                class.live += 1;
+                class.live_bytes += layout.size();
+                true
+            },
+            None => {
+                self.dropped += 1;
+                false
+            },
+        }
+    }
+
+    // Returns false if addr wasn't recorded
+    fn forget(&mut self, addr: usize) -> bool {
+        match self.records.iter_mut().find(|record| record.addr == addr) {
+            Some(record) => {
+                let class = &mut self.histogram[size_class(record.size)];
+                class.live -= 1;
+                class.live_bytes -= record.size;
+                *record = AllocationRecord::EMPTY;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn live(&self) -> impl Iterator<Item = &AllocationRecord> {
+        self.records.iter().filter(|record| record.addr != 0)
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    let class = size.next_power_of_two().trailing_zeros().saturating_sub(3) as usize;
+    core::cmp::min(class, SIZE_CLASSES - 1)
+}
+
+// Wraps an allocator and, while tracking is on, records every live
+// allocation it hands out. The records live in a fixed table so tracking
+// never allocates itself.
+pub struct TrackedHeap<A: GlobalAlloc> {
+    inner: A,
+    tracker: Mutex<Tracker>,
+    // Number of scopes wanting allocations tracked
+    active: AtomicUsize,
+    // Live records, frees skip the table while there are none
+    recorded: AtomicUsize,
+}
+
+impl<A: GlobalAlloc> TrackedHeap<A> {
+    pub const fn new(inner: A) -> TrackedHeap<A> {
+        TrackedHeap {
+            inner,
+            tracker: Mutex::new(Tracker::new()),
+            active: AtomicUsize::new(0),
+            recorded: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    // Tracks allocations until the returned scope is dropped
+    pub fn track(&self) -> TrackingScope<A> {
+        let mut tracker = self.tracker.lock();
+        tracker.generation += 1;
+        self.active.fetch_add(1, Ordering::SeqCst);
+        TrackingScope { heap: self, generation: tracker.generation }
+    }
+
+    // Tracks allocations from now on, for as long as the kernel runs
+    pub fn track_forever(&self) {
+        core::mem::forget(self.track());
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        self.active.load(Ordering::SeqCst) > 0
+    }
+
+    // Tags allocations made from now on. Returns the previous tag so it
+    // can be restored.
+    pub fn set_tag(&self, tag: &'static str) -> &'static str {
+        core::mem::replace(&mut self.tracker.lock().tag, tag)
+    }
+
+    pub fn histogram(&self) -> [SizeClassStats; SIZE_CLASSES] {
+        self.tracker.lock().histogram
+    }
+
+    // Prints every live tracked allocation to the serial port
+    pub fn report_leaks(&self) {
+        let tracker = self.tracker.lock();
+        let (count, bytes) = tracker.live()
+            .fold((0, 0), |(count, bytes), record| (count + 1, bytes + record.size));
+        crate::dbg_println!("{} live allocations, {} bytes", count, bytes);
+        for record in tracker.live() {
+            crate::dbg_println!("  {:#x}: {} bytes, align {} [{}]",
+                                record.addr, record.size, record.align, record.tag);
+        }
+        if tracker.dropped > 0 {
+            crate::dbg_println!("  {} allocations were not recorded", tracker.dropped);
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackedHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() && self.is_tracking() && self.tracker.lock().record(ptr as usize, layout) {
+            self.recorded.fetch_add(1, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // allocations made before tracking started just aren't found
+        if self.recorded.load(Ordering::SeqCst) > 0 && self.tracker.lock().forget(ptr as usize) {
+            self.recorded.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+// Allocations made while a scope is alive count towards it
+pub struct TrackingScope<'a, A: GlobalAlloc> {
+    heap: &'a TrackedHeap<A>,
+    generation: usize,
+}
+
+impl<'a, A: GlobalAlloc> TrackingScope<'a, A> {
+    // Allocations made during the scope that are still live
+    pub fn leaked(&self) -> usize {
+        self.heap.tracker.lock().live()
+            .filter(|record| record.generation >= self.generation)
+            .count()
+    }
+
+    pub fn leaked_bytes(&self) -> usize {
+        self.heap.tracker.lock().live()
+            .filter(|record| record.generation >= self.generation)
+            .map(|record| record.size)
+            .sum()
+    }
+}
+
+impl<'a, A: GlobalAlloc> Drop for TrackingScope<'a, A> {
+    fn drop(&mut self) {
+        self.heap.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
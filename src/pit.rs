@@ -0,0 +1,37 @@
+use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Input clock of the 8253/8254 programmable interval timer
+const PIT_FREQUENCY: u32 = 1_193_182;
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+pub const TIMER_FREQUENCY: u32 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    set_frequency(TIMER_FREQUENCY);
+}
+
+pub fn set_frequency(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz).max(1).min(0xffff) as u16;
+
+    unsafe {
+        let mut command: Port<u8> = Port::new(COMMAND);
+        let mut data: Port<u8> = Port::new(CHANNEL_0);
+        // channel 0, lobyte/hibyte, rate generator
+        command.write(0x36);
+        data.write((divisor & 0xff) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
@@ -0,0 +1,125 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::task::{self, ProcState, TASK_MANAGER};
+use crate::pit;
+
+// Number of timer ticks a task runs for before being preempted
+pub const DEFAULT_TIME_SLICE: usize = 10;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIME_SLICE: AtomicUsize = AtomicUsize::new(DEFAULT_TIME_SLICE);
+static TICKS_LEFT: AtomicUsize = AtomicUsize::new(DEFAULT_TIME_SLICE);
+
+// Must be called after the heap is initialized
+pub fn init() {
+    lazy_static::initialize(&TASK_MANAGER);
+    TICKS_LEFT.store(TIME_SLICE.load(Ordering::SeqCst), Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn set_time_slice(ticks: usize) {
+    let ticks = ticks.max(1);
+    TIME_SLICE.store(ticks, Ordering::SeqCst);
+    TICKS_LEFT.store(ticks, Ordering::SeqCst);
+}
+
+pub fn time_slice() -> usize {
+    TIME_SLICE.load(Ordering::SeqCst)
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+// Called from the timer interrupt after EOI has been sent
+pub fn timer_tick() {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    // Reschedule early if a sleeper is due or only the idle proc is running
+    let wakeup_due = pit::ticks() >= task::NEXT_WAKEUP.load(Ordering::SeqCst);
+    if TICKS_LEFT.fetch_sub(1, Ordering::SeqCst) > 1 && !wakeup_due && !task::current_is_idle() {
+        return;
+    }
+    TICKS_LEFT.store(TIME_SLICE.load(Ordering::SeqCst), Ordering::SeqCst);
+
+    unsafe {
+        task::switch();
+    }
+}
+
+// Gives up the rest of the current time slice
+pub fn yield_now() {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        TICKS_LEFT.store(TIME_SLICE.load(Ordering::SeqCst), Ordering::SeqCst);
+        unsafe {
+            task::switch();
+        }
+    });
+}
+
+pub fn sleep_until(tick: u64) {
+    while pit::ticks() < tick {
+        if !enabled() {
+            x86_64::instructions::hlt();
+            continue;
+        }
+        interrupts::without_interrupts(|| {
+            task::block_current(ProcState::Sleeping, tick);
+        });
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    let ticks = ms.saturating_mul(pit::TIMER_FREQUENCY as u64) / 1000;
+    sleep_until(pit::ticks().saturating_add(ticks.max(1)));
+}
+
+#[cfg(test)]
+use core::sync::atomic::AtomicU64;
+
+#[cfg(test)]
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+extern fn count_forever() {
+    loop {
+        COUNTER.fetch_add(1, Ordering::SeqCst);
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_preemption() {
+    let stack = crate::memory::kernel_stack::KernelStack::new().expect("could not allocate kernel stack");
+    let id = interrupts::without_interrupts(|| {
+        TASK_MANAGER.write().spawn(stack, count_forever)
+            .expect("could not spawn task").read().id
+    });
+
+    // the spawned task only runs if the timer preempts this one
+    let start = crate::pit::ticks();
+    while COUNTER.load(Ordering::SeqCst) == 0 {
+        assert!(crate::pit::ticks() - start < 1000, "spawned task never ran");
+        x86_64::instructions::hlt();
+    }
+
+    interrupts::without_interrupts(|| {
+        TASK_MANAGER.write().remove(id);
+    });
+    crate::task::free_reaped();
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = pit::ticks();
+    sleep_ms(15);
+    assert!(pit::ticks() - start >= 15 * pit::TIMER_FREQUENCY as u64 / 1000);
+}
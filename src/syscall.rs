@@ -0,0 +1,497 @@
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::registers::model_specific::{Msr, Efer, EferFlags};
+
+use crate::gdt;
+use crate::task::{self, EFAULT, EINVAL, ENOSYS, EBADF, ENOMEM, EAGAIN};
+
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+
+// Cleared on entry: interrupts, direction and trap flags
+const SYSCALL_FLAGS_MASK: u64 = 0x700;
+
+// Highest address user pointers may refer to
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// Longest sleep a proc may ask for, a day
+const MAX_SLEEP_MS: u64 = 24 * 60 * 60 * 1000;
+
+pub const SYS_WRITE: usize = 0;
+pub const SYS_EXIT: usize = 1;
+pub const SYS_YIELD: usize = 2;
+pub const SYS_SLEEP: usize = 3;
+pub const SYS_GETPID: usize = 4;
+pub const SYS_MMAP: usize = 5;
+pub const SYS_FORK: usize = 6;
+
+type SyscallHandler = fn(&SyscallFrame) -> i64;
+
+static SYSCALL_TABLE: [SyscallHandler; 7] = [
+    sys_write,
+    sys_exit,
+    sys_yield,
+    sys_sleep,
+    sys_getpid,
+    sys_mmap,
+    sys_fork,
+];
+
+// Used by the entry stub, which can't take a lock. The kernel stack is
+// updated along with the TSS on every context switch.
+#[no_mangle]
+static mut SYSCALL_KERNEL_RSP: u64 = 0;
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+
+// Registers saved by syscall_entry, lowest address first
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    // Arguments follow the System V convention with r10 in place of rcx
+    fn arg(&self, n: usize) -> u64 {
+        match n {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => 0,
+        }
+    }
+}
+
+global_asm!("
+    .intel_syntax noprefix
+    .global syscall_entry
+    syscall_entry:
+        mov [rip + SYSCALL_USER_RSP], rsp
+        mov rsp, [rip + SYSCALL_KERNEL_RSP]
+        push qword ptr [rip + SYSCALL_USER_RSP]
+        push rcx
+        push r11
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        push rdi
+        push rsi
+        push rdx
+        push r10
+        push r8
+        push r9
+        push rax
+
+        mov rdi, rsp
+        sti
+        call syscall_dispatch
+
+    // Forked procs start here with their copy of the frame on the stack
+    .global syscall_return
+    syscall_return:
+        cli
+
+        pop rax
+        pop r9
+        pop r8
+        pop r10
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        pop r11
+        pop rcx
+        pop rsp
+        sysretq
+    .att_syntax
+");
+
+extern "C" {
+    fn syscall_entry();
+}
+
+pub fn init() {
+    let (kernel_code, _) = gdt::kernel_selectors();
+    let (_, user_data) = gdt::user_selectors();
+
+    // SYSRET loads SS from base + 8 and CS from base + 16 with RPL 3,
+    // SYSCALL loads CS from the kernel base and SS from base + 8.
+    let user_base = (user_data.0 & !3) as u64 - 8;
+    let star = (user_base << 48) | ((kernel_code.0 as u64) << 32);
+
+    unsafe {
+        Efer::write(Efer::read() | EferFlags::SYSTEM_CALL_EXTENSIONS);
+        Msr::new(IA32_STAR).write(star);
+        Msr::new(IA32_LSTAR).write(syscall_entry as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_FLAGS_MASK);
+    }
+}
+
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        SYSCALL_KERNEL_RSP = stack_top.as_u64();
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let number = frame.rax as usize;
+    let result = match SYSCALL_TABLE.get(number) {
+        Some(handler) => handler(frame),
+        None => ENOSYS as i64,
+    };
+    frame.rax = result as u64;
+}
+
+// Whether [addr, end) lies in user space, outside of every level 4 entry
+// the kernel's mappings live in
+fn is_user_range(addr: u64, end: u64) -> bool {
+    use crate::memory::address_space::is_user_address;
+
+    if end > USER_SPACE_END || end <= addr {
+        return false;
+    }
+    let (first, last) = (VirtAddr::new(addr).p4_index(), VirtAddr::new(end - 1).p4_index());
+    (usize::from(first)..=usize::from(last))
+        .all(|index| is_user_address(VirtAddr::new((index as u64) << 39)))
+}
+
+// Checks that [addr, addr + len) lies in user space and is mapped with at
+// least the given flags on top of USER_ACCESSIBLE. Buffers the kernel writes
+// to need WRITABLE.
+fn check_user_pages(addr: u64, len: u64, flags: PageTableFlags) -> Result<(), i64> {
+    use x86_64::registers::control::Cr3;
+    use crate::memory::{walk_page_table, phys_mem_offset};
+
+    let end = addr.checked_add(len).ok_or(EFAULT as i64)?;
+    if len == 0 {
+        return Ok(());
+    }
+    if !is_user_range(addr, end) {
+        return Err(EFAULT as i64);
+    }
+
+    let required = flags | PageTableFlags::USER_ACCESSIBLE;
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut page = addr;
+    while page < end {
+        let translation = unsafe { walk_page_table(level_4_table_frame, VirtAddr::new(page), phys_mem_offset()) };
+        match translation {
+            Some(translation) if translation.flags.contains(required) => {
+                let page_size = translation.size.bytes();
+                page = (page & !(page_size - 1)) + page_size;
+            },
+            _ => return Err(EFAULT as i64),
+        }
+    }
+    Ok(())
+}
+
+// A buffer of user memory the kernel only reads
+fn user_buffer(addr: u64, len: u64) -> Result<&'static [u8], i64> {
+    check_user_pages(addr, len, PageTableFlags::empty())?;
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+fn sys_write(frame: &SyscallFrame) -> i64 {
+    let (fd, addr, len) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    let bytes = match user_buffer(addr, len) {
+        Ok(bytes) => bytes,
+        Err(code) => return code,
+    };
+    let text = alloc::string::String::from_utf8_lossy(bytes);
+
+    match fd {
+        1 => crate::print!("{}", text),
+        2 => crate::dbg_print!("{}", text),
+        _ => return EBADF as i64,
+    }
+    len as i64
+}
+
+fn sys_exit(frame: &SyscallFrame) -> i64 {
+    task::exit(frame.arg(0) as i32);
+}
+
+fn sys_yield(_frame: &SyscallFrame) -> i64 {
+    crate::scheduler::yield_now();
+    0
+}
+
+fn sys_sleep(frame: &SyscallFrame) -> i64 {
+    let ms = frame.arg(0);
+    if ms > MAX_SLEEP_MS {
+        return EINVAL as i64;
+    }
+    crate::scheduler::sleep_ms(ms);
+    0
+}
+
+fn sys_getpid(_frame: &SyscallFrame) -> i64 {
+    task::current_id() as i64
+}
+
+// mmap(addr, len): maps zeroed, user accessible memory at a fixed address
+fn sys_mmap(frame: &SyscallFrame) -> i64 {
+    use crate::memory::paging::{MEMORY_MANAGER, MemoryError, active_page_table};
+
+    let (addr, len) = (frame.arg(0), frame.arg(1));
+    if addr == 0 || addr & 0xfff != 0 || len == 0 {
+        return EINVAL as i64;
+    }
+    match addr.checked_add(len) {
+        Some(end) if is_user_range(addr, end) => (),
+        _ => return EINVAL as i64,
+    }
+
+    let mut mapper = unsafe { active_page_table() };
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = match guard.as_mut() {
+        Some(manager) => manager,
+        None => return ENOMEM as i64,
+    };
+
+    // round up to whole pages
+    let size = ((len + 0xfff) & !0xfff) as usize;
+    match manager.request_user_address_space_at(VirtAddr::new(addr), size, &mut mapper) {
+        Ok(region) => {
+            unsafe { core::ptr::write_bytes(region.start.as_mut_ptr::<u8>(), 0, size) };
+            addr as i64
+        },
+        Err(MemoryError::Overlap) => EINVAL as i64,
+        Err(_) => ENOMEM as i64,
+    }
+}
+
+// fork(): returns the child's id to the parent and 0 to the child, EAGAIN
+// when out of memory
+fn sys_fork(frame: &SyscallFrame) -> i64 {
+    use x86_64::instructions::interrupts;
+    use crate::memory::kernel_stack::KernelStack;
+
+    // syscall_entry enabled interrupts, so the memory manager can be
+    // waited for here but not once the task manager is locked
+    let stack = match KernelStack::new() {
+        Some(stack) => stack,
+        None => return EAGAIN as i64,
+    };
+    let space = match task::fork_address_space() {
+        Ok(space) => space,
+        Err(code) => return code as i64,
+    };
+    let result = interrupts::without_interrupts(|| {
+        task::TASK_MANAGER.write().fork(frame, stack, space)
+    });
+    match result {
+        Ok(id) => id as i64,
+        Err(code) => {
+            task::free_reaped();
+            code as i64
+        },
+    }
+}
+
+// A new address space with two user pages in its first free level 4
+// entry, the first pages filled from pages. The first page is executable
+// code, the second one writable. Returns it and where the pages are.
+#[cfg(test)]
+pub fn user_address_space(pages: &[&[u8]]) -> (crate::memory::AddressSpace, VirtAddr) {
+    use x86_64::structures::paging::mapper::MapperAllSizes;
+    use crate::memory::{AddressSpace, phys_mem_offset};
+    use crate::memory::address_space::is_user_address;
+    use crate::memory::paging::{MEMORY_MANAGER, Protection};
+
+    let mut guard = MEMORY_MANAGER.lock();
+    let manager = guard.as_mut().expect("memory manager not installed");
+    let space = AddressSpace::new(&mut manager.frame_allocator)
+        .expect("could not create address space");
+    let addr = (1..256u64)
+        .map(|index| VirtAddr::new(index << 39))
+        .find(|&addr| is_user_address(addr))
+        .expect("no free level 4 entry");
+
+    let mut mapper = unsafe { space.page_table() };
+    manager.request_user_address_space_at(addr, 2 * 4096, &mut mapper)
+        .expect("could not map user pages");
+    for (index, bytes) in pages.iter().enumerate() {
+        let phys = mapper.translate_addr(addr + index as u64 * 4096)
+            .expect("user page is not mapped");
+        let dest = (phys_mem_offset() + phys.as_u64()).as_mut_ptr::<u8>();
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest, bytes.len()) };
+    }
+    let code = Protection::READ | Protection::EXECUTE | Protection::USER;
+    manager.protect(addr, 4096, code, &mut mapper)
+        .expect("could not make user code executable");
+    (space, addr)
+}
+
+// Runs a user proc on space from code_addr with the stack at the end of
+// its pages. Returns the proc's id.
+#[cfg(test)]
+pub fn spawn_user(space: crate::memory::AddressSpace, code_addr: VirtAddr) -> usize {
+    use x86_64::instructions::interrupts;
+    use crate::memory::kernel_stack::KernelStack;
+    use crate::task::TASK_MANAGER;
+
+    let stack_top = code_addr + 2 * 4096u64;
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    interrupts::without_interrupts(|| {
+        let mut manager = TASK_MANAGER.write();
+        let proc = manager.spawn_closure(stack, move || unsafe {
+            crate::usermode::enter_user_mode(code_addr, stack_top)
+        }).expect("could not spawn user task");
+        let mut proc = proc.write();
+        proc.set_address_space(space);
+        proc.id
+    })
+}
+
+// Runs code as a user proc and returns its exit code
+#[cfg(test)]
+pub fn run_user_code(code: &[u8]) -> i32 {
+    let (space, code_addr) = user_address_space(&[code]);
+    task::join(spawn_user(space, code_addr)).expect("could not join user task")
+}
+
+#[test_case]
+fn test_syscall_getpid_exit() {
+    let code = [
+        0xb8, 0x04, 0x00, 0x00, 0x00,       // mov eax, SYS_GETPID
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, SYS_EXIT
+        0x0f, 0x05,                         // syscall
+    ];
+    let pid = run_user_code(&code);
+    assert!(pid > 0);
+}
+
+#[test_case]
+fn test_syscall_write() {
+    let code = [
+        0xb8, 0x00, 0x00, 0x00, 0x00,               // mov eax, SYS_WRITE
+        0xbf, 0x02, 0x00, 0x00, 0x00,               // mov edi, 2
+        0x48, 0x8d, 0x35, 0x10, 0x00, 0x00, 0x00,   // lea rsi, [rip + 16]
+        0xba, 0x05, 0x00, 0x00, 0x00,               // mov edx, 5
+        0x0f, 0x05,                                 // syscall
+        0x89, 0xc7,                                 // mov edi, eax
+        0xb8, 0x01, 0x00, 0x00, 0x00,               // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+        b'h', b'e', b'l', b'l', b'o',
+    ];
+    assert_eq!(run_user_code(&code), 5);
+}
+
+#[test_case]
+fn test_syscall_errors() {
+    let code = [
+        0xb8, 0xe7, 0x03, 0x00, 0x00,       // mov eax, 999
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, SYS_EXIT
+        0x0f, 0x05,                         // syscall
+    ];
+    assert_eq!(run_user_code(&code), ENOSYS);
+}
+
+#[test_case]
+fn test_syscall_sleep_too_long() {
+    let code = [
+        0xb8, 0x03, 0x00, 0x00, 0x00,               // mov eax, SYS_SLEEP
+        0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff,   // mov rdi, -1
+        0x0f, 0x05,                                 // syscall
+        0x89, 0xc7,                                 // mov edi, eax
+        0xb8, 0x01, 0x00, 0x00, 0x00,               // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    assert_eq!(run_user_code(&code), EINVAL);
+}
+
+#[test_case]
+fn test_syscall_kernel_buffer() {
+    // the kernel heap is mapped, but not for user code
+    let code = [
+        0xb8, 0x00, 0x00, 0x00, 0x00,                               // mov eax, SYS_WRITE
+        0xbf, 0x02, 0x00, 0x00, 0x00,                               // mov edi, 2
+        0x48, 0xbe, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00, // mov rsi, HEAP_START
+        0xba, 0x05, 0x00, 0x00, 0x00,                               // mov edx, 5
+        0x0f, 0x05,                                                 // syscall
+        0x89, 0xc7,                                                 // mov edi, eax
+        0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, SYS_EXIT
+        0x0f, 0x05,                                                 // syscall
+    ];
+    assert_eq!(run_user_code(&code), EFAULT);
+}
+
+#[test_case]
+fn test_syscall_fork() {
+    use crate::memory::paging::MEMORY_MANAGER;
+
+    // The child adds 2 to the data page and exits with it, the parent adds
+    // 10 and exits with the child's id if it sees 15.
+    let code = [
+        0x48, 0xc7, 0xc0, 0x06, 0x00, 0x00, 0x00,       // mov rax, SYS_FORK
+        0x0f, 0x05,                                     // syscall
+        0x48, 0x85, 0xc0,                               // test rax, rax
+        0x75, 0x18,                                     // jnz parent
+        0x48, 0x83, 0x05, 0xea, 0x0f, 0x00, 0x00, 0x02, // add qword [rip + data], 2
+        0x48, 0x8b, 0x3d, 0xe3, 0x0f, 0x00, 0x00,       // mov rdi, [rip + data]
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00,       // mov rax, SYS_EXIT
+        0x0f, 0x05,                                     // syscall
+        0x48, 0x89, 0xc3,                               // parent: mov rbx, rax
+        0x48, 0x83, 0x05, 0xcf, 0x0f, 0x00, 0x00, 0x0a, // add qword [rip + data], 10
+        0x48, 0x89, 0xdf,                               // mov rdi, rbx
+        0x48, 0x83, 0x3d, 0xc4, 0x0f, 0x00, 0x00, 0x0f, // cmp qword [rip + data], 15
+        0x74, 0x07,                                     // je done
+        0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff,       // mov rdi, -1
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00,       // done: mov rax, SYS_EXIT
+        0x0f, 0x05,                                     // syscall
+    ];
+    let data = 5u64.to_le_bytes();
+
+    let used_frames = || MEMORY_MANAGER.lock().as_ref()
+        .expect("memory manager not installed")
+        .frame_allocator.used_frames();
+    let used = used_frames();
+
+    let (space, code_addr) = user_address_space(&[&code[..], &data[..]]);
+    let parent = spawn_user(space, code_addr);
+
+    let child = task::join(parent).expect("could not join parent");
+    assert!(child > 0);
+    assert_eq!(task::join(child as usize), Ok(7));
+
+    // both address spaces are gone along with every copied page
+    assert_eq!(used_frames(), used);
+
+    // kernel threads have no address space of their own to copy
+    assert_eq!(task::fork_address_space().err(), Some(EINVAL));
+}
@@ -1,24 +1,70 @@
-use alloc::{boxed::Box, vec, vec::Vec, collections::BTreeMap};
-use core::sync::atomic::AtomicBool;
+use alloc::{boxed::Box, vec::Vec, collections::BTreeMap};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use core::mem;
-use core::alloc::{GlobalAlloc, Layout};
 
 use alloc::sync::Arc;
 use spin::RwLock;
+use lazy_static::lazy_static;
 
+// Read by switch_context, which releases it
+#[no_mangle]
 pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
+use crate::wait::WaitQueue;
+use crate::memory::AddressSpace;
+use crate::memory::kernel_stack::KernelStack;
+use crate::syscall::SyscallFrame;
+
+lazy_static! {
+    // Must not be touched before the heap is initialized
+    pub static ref TASK_MANAGER: RwLock<TaskManager> = RwLock::new(TaskManager::new());
+    // Woken whenever a proc exits
+    static ref EXIT_QUEUE: WaitQueue = WaitQueue::new();
+}
+
+// One bit per proc id. Wakeups are recorded here so interrupt handlers
+// never need the task manager lock; they are applied on the next switch.
+const PENDING_WAKEUP_WORDS: usize = 4;
+static PENDING_WAKEUPS: [AtomicU64; PENDING_WAKEUP_WORDS] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)
+];
+
+// Id of the running proc, for code that can't take the task manager lock
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+// Earliest tick at which a sleeping proc needs to be woken
+pub static NEXT_WAKEUP: AtomicU64 = AtomicU64::new(u64::max_value());
+
+// Interrupts enabled, reserved bit 1 set
+const INITIAL_RFLAGS: usize = 0x202;
+
 // FIXME: Arbitrary number of processes
 pub static MAX_PROCS: usize = 256;
 
 // FIXME: rough draft error codes
 pub static EAGAIN: i32 = -2;
+pub static ESRCH: i32 = -3;
+pub static EDEADLK: i32 = -4;
+pub static ENOSYS: i32 = -5;
+pub static EFAULT: i32 = -6;
+pub static EINVAL: i32 = -7;
+pub static EBADF: i32 = -8;
+pub static ENOMEM: i32 = -9;
 
 pub struct TaskManager {
 
     procs: BTreeMap<usize, Arc::<RwLock<Proc>>>,
-    next_id: usize
+    next_id: usize,
+
+    current: usize,
+    // runs when no other proc is runnable
+    idle: usize,
+    // Removed procs and the stacks and address spaces of failed spawns
+    // and forks, waiting for free_reaped
+    reaped: Vec<Arc<RwLock<Proc>>>,
+    unused_stacks: Vec<KernelStack>,
+    unused_spaces: Vec<AddressSpace>,
 
     //current_task: usize,
     //num_tasks: usize
@@ -28,10 +74,47 @@ pub struct TaskManager {
 impl TaskManager {
 
     pub fn new() -> TaskManager {
-        TaskManager {
-            procs: BTreeMap::new(),
-            next_id: 1
-        }
+        // Proc 0 is the context that booted the kernel. Its registers are
+        // filled in the first time it is switched away from.
+        let mut kernel_proc = Proc::from(0);
+        kernel_proc.state = ProcState::Running;
+        let fx = alloc_fx();
+        kernel_proc.cpu_context.set_fx(fx.address());
+        kernel_proc.kfx = Some(fx);
+
+        let mut procs = BTreeMap::new();
+        procs.insert(0, Arc::new(RwLock::new(kernel_proc)));
+
+        let mut manager = TaskManager {
+            procs,
+            next_id: 1,
+            current: 0,
+            idle: 0,
+            reaped: Vec::new(),
+            unused_stacks: Vec::new(),
+            unused_spaces: Vec::new(),
+        };
+
+        // nothing else uses the memory manager this early
+        let stack = KernelStack::new().expect("could not allocate idle stack");
+        manager.idle = manager.spawn_closure(stack, || loop {
+            free_reaped();
+            x86_64::instructions::hlt();
+        }).expect("could not spawn idle proc").read().id;
+
+        manager
+    }
+
+    pub fn current_id(&self) -> usize {
+        self.current
+    }
+
+    pub fn idle_id(&self) -> usize {
+        self.idle
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Arc<RwLock<Proc>>> {
+        self.procs.get(&id)
     }
     
     pub fn new_proc(&mut self) -> 
@@ -51,55 +134,568 @@ impl TaskManager {
             let p = Proc::from(self.next_id);
             self.next_id += 1;
 
+            let id = p.id;
+            self.procs.insert(id, Arc::new(RwLock::new(p)));
+
             Ok(self.procs
-               .get(&p.id)
+               .get(&id)
                .expect("Failed to create new process"))
 
     }
 
-    pub fn remove(&mut self, id: usize) -> Option<Arc<RwLock<Proc>>> {
-        self.procs.remove(&id)
+    // The proc is freed by the next free_reaped
+    pub fn remove(&mut self, id: usize) {
+        self.release(id);
     }
 
-    pub fn spawn(&mut self, func: extern fn()) -> 
+    pub fn spawn(&mut self, stack: KernelStack, func: extern fn()) ->
         Result<&Arc<RwLock<Proc>>, i32> {
-            let proc_lock = self.new_proc()?;
+            self.spawn_closure(stack, move || {
+                func();
+                0
+            })
+    }
+
+    pub fn spawn_with_arg(&mut self, stack: KernelStack, func: extern fn(usize) -> i32, arg: usize) ->
+        Result<&Arc<RwLock<Proc>>, i32> {
+            self.spawn_closure(stack, move || func(arg))
+    }
+
+    // Runs func in a new kernel thread on stack. Allocating the stack waits
+    // for the memory manager, so callers do it before locking the task
+    // manager.
+    pub fn spawn_closure<F>(&mut self, stack: KernelStack, func: F) -> Result<&Arc<RwLock<Proc>>, i32>
+        where F: FnOnce() -> i32 + Send + 'static {
+            self.reap_detached();
+
+            let id = match self.new_proc() {
+                Ok(proc) => proc.read().id,
+                Err(code) => {
+                    self.unused_stacks.push(stack);
+                    return Err(code);
+                },
+            };
+            let proc_lock = &self.procs[&id];
             {
                 let mut proc = proc_lock.write();
-                let mut fx = unsafe { Box::from_raw(crate::memory::allocator::ALLOCATOR.alloc(Layout::from_size_align_unchecked(512, 16)) as *mut [u8; 512]) };
-
-                for b in fx.iter_mut() {
-                    *b = 0;
-                }
+                let fx = alloc_fx();
+                stack.set_owner(id);
 
-                let mut stack = vec![0; 65_536].into_boxed_slice();
-                let offset = stack.len() - mem::size_of::<usize>();
+                // The first switch to this proc returns into the trampoline.
+                // The stack is 16 byte aligned once that address is popped.
+                let rsp = stack.top().as_u64() as usize - mem::size_of::<usize>();
 
                 unsafe {
-                    let offset = stack.len() - mem::size_of::<usize>();
-                    let func_ptr = stack.as_mut_ptr().add(offset);
-                    *(func_ptr as *mut usize) = func as usize;
+                    *(rsp as *mut usize) = thread_trampoline as usize;
                 }
 
-                //proc.cpu_context.set_page_table( 
+                // Handed to thread_entry through rbx
+                let entry: Box<ThreadEntry> = Box::new(Box::new(func));
+                let entry = Box::into_raw(entry) as usize;
+                proc.cpu_context.set_rbx(entry);
+                proc.entry = Some(entry);
 
-                proc.cpu_context.set_fx(fx.as_ptr() as usize);
-                proc.cpu_context.set_stack(stack.as_ptr() as usize + offset);
+                // kernel threads share the kernel's page table
+                let kernel_table = crate::memory::address_space::kernel_page_table();
+                proc.cpu_context.set_page_table(kernel_table.start_address().as_u64() as usize);
+                proc.cpu_context.set_rflags(INITIAL_RFLAGS);
+
+                proc.cpu_context.set_fx(fx.address());
+                proc.cpu_context.set_stack(rsp);
                 proc.kfx = Some(fx);
                 proc.kstack = Some(stack);
+                proc.state = ProcState::Runnable;
             }
         Ok(proc_lock)
     }
+
+    // Creates a copy of the current proc running on stack in space, which
+    // fork_address_space built from the current proc's address space. The
+    // child returns from the syscall with 0. Returns the child's id.
+    pub fn fork(&mut self, frame: &SyscallFrame, stack: KernelStack, space: AddressSpace) ->
+        Result<usize, i32> {
+            let parent_lock = match self.procs.get(&self.current) {
+                Some(parent) => parent.clone(),
+                None => return Err(self.fork_failed(stack, space, ESRCH)),
+            };
+            let id = match self.new_proc() {
+                Ok(proc) => proc.read().id,
+                Err(code) => return Err(self.fork_failed(stack, space, code)),
+            };
+            let parent = parent_lock.read();
+            let mut child = self.procs[&id].write();
+            let fx = alloc_fx();
+            stack.set_owner(id);
+
+            // The child's first switch returns into the syscall exit path
+            // with a copy of the parent's registers where syscall_entry left
+            // them
+            let frame_ptr = stack.top().as_u64() as usize - mem::size_of::<SyscallFrame>();
+            let rsp = frame_ptr - mem::size_of::<usize>();
+            let mut child_frame = frame.clone();
+            child_frame.rax = 0;
+            unsafe {
+                *(frame_ptr as *mut SyscallFrame) = child_frame;
+                *(rsp as *mut usize) = syscall_return as usize;
+            }
+
+            // The rest of the registers are restored from the frame. The FPU
+            // starts out fresh rather than from the parent's stale save area.
+            child.cpu_context = parent.cpu_context.clone();
+            child.cpu_context.loadable = false;
+            child.cpu_context.set_rflags(INITIAL_RFLAGS);
+            child.cpu_context.set_fx(fx.address());
+            child.cpu_context.set_stack(rsp);
+            child.set_address_space(space);
+            child.kfx = Some(fx);
+            child.kstack = Some(stack);
+            child.state = ProcState::Runnable;
+
+            Ok(id)
+    }
+
+    // Keeps what a failed fork was given for free_reaped
+    fn fork_failed(&mut self, stack: KernelStack, space: AddressSpace, code: i32) -> i32 {
+        self.unused_stacks.push(stack);
+        self.unused_spaces.push(space);
+        code
+    }
+
+    // Returns the exit code of a zombie proc and frees it. Procs that are
+    // still running yield an EAGAIN error.
+    pub fn reap(&mut self, id: usize) -> Result<i32, i32> {
+        let (state, exit_code) = match self.procs.get(&id) {
+            Some(proc) => {
+                let proc = proc.read();
+                (proc.state, proc.exit_code)
+            },
+            None => return Err(ESRCH),
+        };
+
+        match exit_code {
+            Some(code) if state == ProcState::Zombie && id != self.current => {
+                self.release(id);
+                Ok(code)
+            },
+            _ => Err(EAGAIN),
+        }
+    }
+
+    // Detached procs are freed as soon as they exit instead of waiting
+    // for a join.
+    pub fn detach(&mut self, id: usize) -> Result<(), i32> {
+        match self.procs.get(&id) {
+            Some(proc) => {
+                proc.write().detached = true;
+                Ok(())
+            },
+            None => Err(ESRCH),
+        }
+    }
+
+    fn reap_detached(&mut self) {
+        let current = self.current;
+        let zombies: Vec<usize> = self.procs.iter()
+            .filter(|(&id, proc)| {
+                let proc = proc.read();
+                id != current && proc.detached && proc.state == ProcState::Zombie
+            })
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in zombies {
+            self.release(id);
+        }
+    }
+
+    // Dropping a proc frees its address space and kernel stack, which
+    // waits for the memory manager. That must not happen here with the
+    // task manager locked, so the proc is kept for free_reaped.
+    fn release(&mut self, id: usize) {
+        if let Some(proc) = self.procs.remove(&id) {
+            self.reaped.push(proc);
+        }
+    }
+
+    // Moves blocked and sleeping procs whose wakeup has arrived back to
+    // Runnable and recomputes NEXT_WAKEUP.
+    fn apply_wakeups(&mut self) {
+        let now = crate::pit::ticks();
+        let mut next_wakeup = u64::max_value();
+
+        for (&id, proc) in self.procs.iter() {
+            // leave the bit pending if the proc can't be updated right now
+            let mut proc = match proc.try_write() {
+                Some(proc) => proc,
+                None => continue,
+            };
+            let woken = take_wakeup(id);
+
+            match proc.state {
+                ProcState::Blocked if woken => proc.state = ProcState::Runnable,
+                ProcState::Sleeping if woken || proc.wake_at <= now => {
+                    proc.state = ProcState::Runnable
+                },
+                ProcState::Sleeping => next_wakeup = next_wakeup.min(proc.wake_at),
+                _ => (),
+            }
+        }
+
+        NEXT_WAKEUP.store(next_wakeup, Ordering::SeqCst);
+    }
+
+    // Round robin: the first runnable proc after the current one by id
+    fn next_runnable(&self) -> Option<usize> {
+        let after = self.procs.range(self.current + 1..);
+        let before = self.procs.range(..self.current);
+
+        after.chain(before)
+            .find(|&(&id, proc)| {
+                id != self.idle && proc.try_read()
+                    .map_or(false, |proc| proc.state == ProcState::Runnable)
+            })
+            .map(|(&id, _)| id)
+    }
+
+    // Picks the next proc to run and returns pointers to the current and
+    // next contexts. The pointers stay valid as long as both procs remain
+    // in the proc table, which CONTEXT_SWITCH_LOCK guarantees.
+    fn next_contexts(&mut self) -> Option<(*mut CPUContext, *mut CPUContext)> {
+        self.apply_wakeups();
+
+        let prev_lock = self.procs.get(&self.current)?.clone();
+        let prev_stopped = prev_lock.try_read()
+            .map_or(false, |proc| proc.state != ProcState::Running);
+
+        let next_id = match self.next_runnable() {
+            Some(id) => id,
+            // nothing else wants the CPU; idle only if the current proc
+            // has blocked, slept or exited
+            None if prev_stopped => self.idle,
+            None => return None,
+        };
+        if next_id == self.current {
+            return None;
+        }
+        let next_lock = self.procs[&next_id].clone();
+
+        let prev_ptr = {
+            let mut prev = prev_lock.try_write()?;
+            if prev.state == ProcState::Running {
+                prev.state = ProcState::Runnable;
+            }
+            &mut prev.cpu_context as *mut CPUContext
+        };
+        let next_ptr = {
+            let mut next = next_lock.try_write()?;
+            next.state = ProcState::Running;
+            // thread_entry owns the closure from here on
+            next.entry = None;
+            // interrupts from ring 3 land on the proc's own kernel stack
+            if let Some(stack_top) = next.kernel_stack_top() {
+                crate::gdt::set_kernel_stack(stack_top);
+            }
+            &mut next.cpu_context as *mut CPUContext
+        };
+
+        self.current = next_id;
+        RUNNING.store(next_id, Ordering::SeqCst);
+        Some((prev_ptr, next_ptr))
+    }
+}
+
+// Switches to the next runnable proc. Returns false if there was nothing
+// to switch to or another switch is already in progress.
+pub unsafe fn switch() -> bool {
+    if CONTEXT_SWITCH_LOCK.compare_and_swap(false, true, Ordering::SeqCst) {
+        return false;
+    }
+
+    let contexts = match TASK_MANAGER.try_write() {
+        Some(mut manager) => manager.next_contexts(),
+        None => None,
+    };
+
+    match contexts {
+        Some((prev, next)) => {
+            // CPUContext::switch releases CONTEXT_SWITCH_LOCK
+            (&mut *prev).switch(&mut *next);
+            true
+        },
+        None => {
+            CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
+            false
+        }
+    }
+}
+
+pub fn current_id() -> usize {
+    TASK_MANAGER.read().current_id()
+}
+
+// Like current_id but safe from interrupt handlers
+pub fn running_id() -> usize {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+pub fn current_is_idle() -> bool {
+    TASK_MANAGER.try_read().map_or(false, |manager| manager.current == manager.idle)
+}
+
+// Marks a blocked or sleeping proc as runnable. Safe to call from
+// interrupt handlers.
+pub fn wake(id: usize) {
+    if id < PENDING_WAKEUP_WORDS * 64 {
+        PENDING_WAKEUPS[id / 64].fetch_or(1 << (id % 64), Ordering::SeqCst);
+    }
+}
+
+fn take_wakeup(id: usize) -> bool {
+    if id >= PENDING_WAKEUP_WORDS * 64 {
+        return false;
+    }
+    let bit = 1 << (id % 64);
+    PENDING_WAKEUPS[id / 64].fetch_and(!bit, Ordering::SeqCst) & bit != 0
+}
+
+// Puts the current proc in the given state and switches away. Must be
+// called with interrupts disabled so that a wakeup can't be missed between
+// registering on a wait queue and blocking.
+pub fn block_current(state: ProcState, wake_at: u64) {
+    {
+        let manager = TASK_MANAGER.read();
+        let mut current = manager.get(manager.current_id())
+            .expect("current proc is missing")
+            .write();
+        current.state = state;
+        current.wake_at = wake_at;
+    }
+
+    if state == ProcState::Sleeping {
+        let mut next = NEXT_WAKEUP.load(Ordering::SeqCst);
+        while wake_at < next {
+            next = NEXT_WAKEUP.compare_and_swap(next, wake_at, Ordering::SeqCst);
+        }
+    }
+
+    unsafe {
+        switch();
+    }
+}
+
+type ThreadEntry = Box<dyn FnOnce() -> i32 + Send>;
+
+// New kernel threads start here with a pointer to their ThreadEntry in rbx
+global_asm!("
+    .intel_syntax noprefix
+    .global thread_trampoline
+    thread_trampoline:
+        mov rdi, rbx
+        call thread_entry
+        ud2
+    .att_syntax
+");
+
+extern "C" {
+    fn thread_trampoline();
+    // The end of syscall_entry, which restores a SyscallFrame and returns
+    // to user mode
+    fn syscall_return();
+}
+
+#[no_mangle]
+extern "C" fn thread_entry(entry: usize) -> ! {
+    let func = unsafe { Box::from_raw(entry as *mut ThreadEntry) };
+    let code = func();
+    exit(code);
+}
+
+// Terminates the current proc. It stays a zombie holding its exit code
+// until it is joined or, if detached, reaped.
+pub fn exit(code: i32) -> ! {
+    use x86_64::instructions::interrupts;
+
+    if let Some(space) = leave_address_space() {
+        let mut guard = crate::memory::paging::MEMORY_MANAGER.lock();
+        if let Some(manager) = guard.as_mut() {
+            manager.destroy_address_space(space);
+        }
+    }
+
+    {
+        let manager = TASK_MANAGER.read();
+        let current = manager.get(manager.current_id())
+            .expect("current proc is missing");
+        let mut current = current.write();
+        current.exit_code = Some(code);
+        current.state = ProcState::Zombie;
+    }
+    EXIT_QUEUE.wake_all();
+
+    // A zombie is never scheduled again, so once switched away from (here
+    // or by the timer) this proc doesn't run again.
+    loop {
+        interrupts::disable();
+        unsafe {
+            if !switch() {
+                // someone else holds the task manager, try again later
+                interrupts::enable_interrupts_and_hlt();
+            }
+        }
+    }
+}
+
+// Moves the current proc onto the kernel's page table and hands back the
+// address space it was using. Everything a proc touches while exiting is
+// mapped there too.
+fn leave_address_space() -> Option<AddressSpace> {
+    use x86_64::instructions::interrupts;
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+
+    interrupts::without_interrupts(|| {
+        let manager = TASK_MANAGER.read();
+        let mut current = manager.get(manager.current_id())
+            .expect("current proc is missing")
+            .write();
+        let space = current.address_space.take()?;
+
+        let kernel = crate::memory::address_space::kernel_page_table();
+        current.cpu_context.set_page_table(kernel.start_address().as_u64() as usize);
+        unsafe {
+            Cr3::write(kernel, Cr3Flags::empty());
+        }
+        Some(space)
+    })
+}
+
+// Frees the procs reaped and stacks left unused since the last call. Must
+// be called with interrupts enabled and without holding the task manager.
+// If the task manager is busy they are left for the next call.
+pub fn free_reaped() {
+    use x86_64::instructions::interrupts;
+
+    let reaped = interrupts::without_interrupts(|| {
+        TASK_MANAGER.try_write().map(|mut manager| {
+            (mem::replace(&mut manager.reaped, Vec::new()),
+             mem::replace(&mut manager.unused_stacks, Vec::new()),
+             mem::replace(&mut manager.unused_spaces, Vec::new()))
+        })
+    });
+    if let Some((procs, stacks, spaces)) = reaped {
+        drop(procs);
+        drop(stacks);
+        if !spaces.is_empty() {
+            let mut guard = crate::memory::paging::MEMORY_MANAGER.lock();
+            if let Some(manager) = guard.as_mut() {
+                for space in spaces {
+                    manager.destroy_address_space(space);
+                }
+            }
+        }
+    }
+}
+
+// Copies the current proc's address space for fork. The proc lends it out
+// meanwhile, so that the memory manager can be waited for without holding
+// the task manager. Must be called with interrupts enabled. Fails with
+// EINVAL for kernel threads, which share the kernel's page table, and with
+// EAGAIN when out of frames.
+pub fn fork_address_space() -> Result<AddressSpace, i32> {
+    let parent = swap_address_space(None).ok_or(EINVAL)?;
+    // the page table stays active, only the handle is lent out
+    let child = crate::memory::paging::MEMORY_MANAGER.lock().as_mut()
+        .and_then(|manager| manager.fork_address_space(&parent))
+        .ok_or(EAGAIN);
+    swap_address_space(Some(parent));
+    child
+}
+
+// Replaces the current proc's address space handle without touching its
+// page table
+fn swap_address_space(space: Option<AddressSpace>) -> Option<AddressSpace> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let manager = TASK_MANAGER.read();
+        let mut current = manager.get(manager.current_id())
+            .expect("current proc is missing")
+            .write();
+        mem::replace(&mut current.address_space, space)
+    })
+}
+
+// Waits for the proc to exit then frees it and returns its exit code
+pub fn join(id: usize) -> Result<i32, i32> {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        let result = interrupts::without_interrupts(|| {
+            let mut manager = TASK_MANAGER.write();
+            if id == manager.current_id() {
+                return Err(EDEADLK);
+            }
+            manager.reap(id)
+        });
+        if result.is_ok() {
+            free_reaped();
+        }
+
+        match result {
+            Err(code) if code == EAGAIN => EXIT_QUEUE.wait_until(|| {
+                TASK_MANAGER.read().get(id)
+                    .map_or(true, |proc| proc.read().state == ProcState::Zombie)
+            }),
+            result => return result,
+        }
+    }
+}
+
+// fxsave requires a 16 byte aligned 512 byte area
+#[repr(C, align(16))]
+pub struct FxArea([u8; 512]);
+
+impl FxArea {
+    fn address(&self) -> usize {
+        self as *const FxArea as usize
+    }
+}
+
+fn alloc_fx() -> Box<FxArea> {
+    Box::new(FxArea([0; 512]))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcState {
+    // Created but not yet set up to run
+    New,
+    Runnable,
+    Running,
+    // Waiting on a wait queue
+    Blocked,
+    // Waiting for the tick in wake_at
+    Sleeping,
+    // Exited, waiting to be joined or reaped
+    Zombie,
 }
 
 // Rudimentary process structure
 pub struct Proc {
     pub id: usize,
-    pub running: bool,
+    pub state: ProcState,
+    pub wake_at: u64,
     pub cpu_context: CPUContext,
 
-    pub kfx: Option<Box<[u8]>>,
-    pub kstack: Option<Box<[u8]>>
+    pub kfx: Option<Box<FxArea>>,
+    pub kstack: Option<KernelStack>,
+    // The Box<ThreadEntry> handed over in rbx until the proc first runs
+    entry: Option<usize>,
+
+    // Set once the proc has exited
+    pub exit_code: Option<i32>,
+    pub detached: bool,
+
+    // None for kernel threads, which run on the kernel's page table
+    pub address_space: Option<AddressSpace>,
 
 }
 
@@ -108,16 +704,52 @@ impl Proc {
     pub fn from(id: usize) -> Self {
         Proc {
             id,
-            running: false,
+            state: ProcState::New,
+            wake_at: 0,
             cpu_context: CPUContext::new(),
             kfx: None,
             kstack: None,
+            entry: None,
+            exit_code: None,
+            detached: false,
+            address_space: None,
+        }
+    }
+
+    // Runs the proc on its own page table from the next switch on. The
+    // address space is freed when the proc exits.
+    pub fn set_address_space(&mut self, space: AddressSpace) {
+        let table = space.level_4_frame().start_address().as_u64();
+        self.cpu_context.set_page_table(table as usize);
+        self.address_space = Some(space);
+    }
+
+    pub fn kernel_stack_top(&self) -> Option<x86_64::VirtAddr> {
+        self.kstack.as_ref().map(|stack| stack.top())
+    }
+}
+
+impl Drop for Proc {
+    fn drop(&mut self) {
+        // A thread that never ran still owns its closure
+        if let Some(entry) = self.entry.take() {
+            unsafe {
+                drop(Box::from_raw(entry as *mut ThreadEntry));
+            }
+        }
+        // Procs that exited gave their address space back already
+        if let Some(space) = self.address_space.take() {
+            if let Some(manager) = crate::memory::paging::MEMORY_MANAGER.lock().as_mut() {
+                manager.destroy_address_space(space);
+            }
         }
     }
 }
 
-// The state of the cpu during execution
+// The state of the cpu during execution. switch_context relies on the
+// layout.
 #[derive(Clone, Debug)]
+#[repr(C)]
 pub struct CPUContext {
 
     loadable: bool,
@@ -171,68 +803,186 @@ impl CPUContext {
         self.fx = address;
     }
 
+    pub fn set_rflags(&mut self, rflags: usize) {
+        self.rflags = rflags;
+    }
+
+    pub fn set_rbx(&mut self, rbx: usize) {
+        self.rbx = rbx;
+    }
+
+    // Saves the current registers here and continues with next's. Returns
+    // once something switches back to this context.
     pub unsafe fn switch(&mut self, next: &mut CPUContext) {
-        // Save the floating point register
-        asm!("fxsave64 [$0]" : :"r"(self.fx) : "memory": "intel", "volatile");
-        self.loadable = true;
-
-        if next.loadable {
-            asm!("fxrstor64 [$0]" : : "r"(next.fx) : "memory" : 
-                 "intel", "volatile");
-        } else { 
-            asm!("fninit": : : "memory" :
-                 "intel", "volatile");
-        }
-
-        // move the current cr3 (page table address) into the structure
-        asm!("mov $0, cr3": : "r"(self.cr3) : :"memory" : 
-             "intel", "volatile");
-        // check if the cr3 needs to be updated 
-        if next.cr3 != self.cr3 {
-            asm!("mov cr3, $0" : : "r"(next.cr3) : "memory" : 
-                 "intel", "volatile");
-        }
-
-        // preserve then update the CPU registers
-        asm!("pushfq ; pop $0" : "=r"(self.rflags) : : 
-             "memory" : "intel", "volatile");
-        asm!("push $0 ; popfq" : : "r"(next.rflags) : 
-             "memory" : "intel", "volatile");
-
-        asm!("mov $0, rbx" : "=r"(self.rbx) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov rbx, $0" : : "r"(next.rbx) : "memory" : 
-             "intel", "volatile");
-
-        asm!("mov $0, r12" : "=r"(self.r12) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov r12, $0" : : "r"(next.r12) : "memory" : 
-             "intel", "volatile");
-
-        asm!("mov $0, r13" : "=r"(self.r13) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov r13, $0" : : "r"(next.r13) : "memory" : 
-             "intel", "volatile");
-
-        asm!("mov $0, r14" : "=r"(self.r14) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov r14, $0" : : "r"(next.r14) : "memory" : 
-             "intel", "volatile");
-
-        asm!("mov $0, r15" : "=r"(self.r15) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov r15, $0" : : "r"(next.r15) : "memory" : 
-             "intel", "volatile");
-
-        asm!("mov $0, rsp" : "=r"(self.rsp) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov rsp, $0" : : "r"(next.rsp) : "memory" : 
-             "intel", "volatile");
-
-        asm!("mov $0, rbp" : "=r"(self.rbp) : : "memory" : 
-             "intel", "volatile");
-        asm!("mov rbp, $0" : : "r"(next.rbp) : "memory" : 
-             "intel", "volatile");
+        switch_context(self, next);
+    }
+}
+
+// Field offsets below follow CPUContext's repr(C) layout
+global_asm!("
+    .intel_syntax noprefix
+    .global switch_context
+    switch_context:
+        // save the floating point registers, and restore next's if it
+        // has any yet
+        mov rax, [rdi + 8]
+        fxsave64 [rax]
+        mov byte ptr [rdi], 1
+        cmp byte ptr [rsi], 0
+        je .Lswitch_fninit
+        mov rax, [rsi + 8]
+        fxrstor64 [rax]
+        jmp .Lswitch_page_table
+    .Lswitch_fninit:
+        fninit
+    .Lswitch_page_table:
+        // only reload cr3 if the page table changes
+        mov rax, cr3
+        mov [rdi + 16], rax
+        mov rcx, [rsi + 16]
+        cmp rax, rcx
+        je .Lswitch_registers
+        mov cr3, rcx
+    .Lswitch_registers:
+        pushfq
+        pop qword ptr [rdi + 24]
+        mov [rdi + 32], rbx
+        mov [rdi + 40], r12
+        mov [rdi + 48], r13
+        mov [rdi + 56], r14
+        mov [rdi + 64], r15
+        mov [rdi + 72], rbp
+        mov [rdi + 80], rsp
+
+        mov rbx, [rsi + 32]
+        mov r12, [rsi + 40]
+        mov r13, [rsi + 48]
+        mov r14, [rsi + 56]
+        mov r15, [rsi + 64]
+        mov rbp, [rsi + 72]
+        mov rsp, [rsi + 80]
+
+        // release the lock once the registers are loaded, before rflags
+        // can enable interrupts again
+        xor eax, eax
+        xchg [rip + CONTEXT_SWITCH_LOCK], al
+        push qword ptr [rsi + 24]
+        popfq
+        ret
+    .att_syntax
+");
+
+extern "C" {
+    fn switch_context(prev: *mut CPUContext, next: *const CPUContext);
+}
 
+#[cfg(test)]
+extern fn double_arg(arg: usize) -> i32 {
+    (arg * 2) as i32
+}
+
+#[test_case]
+fn test_thread_join() {
+    use x86_64::instructions::interrupts;
+
+    let stacks = (KernelStack::new().expect("could not allocate kernel stack"),
+                  KernelStack::new().expect("could not allocate kernel stack"));
+    let (closure_id, arg_id) = interrupts::without_interrupts(|| {
+        let mut manager = TASK_MANAGER.write();
+        let value = 40;
+        let closure_id = manager.spawn_closure(stacks.0, move || value + 2)
+            .expect("could not spawn closure").read().id;
+        let arg_id = manager.spawn_with_arg(stacks.1, double_arg, 21)
+            .expect("could not spawn function").read().id;
+        (closure_id, arg_id)
+    });
+
+    assert_eq!(join(closure_id), Ok(42));
+    assert_eq!(join(arg_id), Ok(42));
+
+    // joined procs are reaped
+    let manager = TASK_MANAGER.read();
+    assert!(manager.get(closure_id).is_none());
+    assert!(manager.get(arg_id).is_none());
+    drop(manager);
+    assert_eq!(join(closure_id), Err(ESRCH));
+}
+
+#[test_case]
+fn test_thread_detach() {
+    use x86_64::instructions::interrupts;
+
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    let id = interrupts::without_interrupts(|| {
+        let mut manager = TASK_MANAGER.write();
+        let id = manager.spawn_closure(stack, || 0).expect("could not spawn").read().id;
+        manager.detach(id).expect("could not detach");
+        id
+    });
+
+    // wait for the detached proc to exit
+    while !TASK_MANAGER.read().get(id).map_or(true, |p| p.read().state == ProcState::Zombie) {
+        crate::scheduler::yield_now();
     }
+
+    // spawning reaps detached zombies
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    let other = interrupts::without_interrupts(|| {
+        TASK_MANAGER.write().spawn_closure(stack, || 0).expect("could not spawn").read().id
+    });
+    assert!(TASK_MANAGER.read().get(id).is_none());
+    assert_eq!(join(other), Ok(0));
+}
+
+#[test_case]
+fn test_spawn_with_memory_manager_locked() {
+    use x86_64::instructions::interrupts;
+    use crate::memory::paging::MEMORY_MANAGER;
+
+    // only allocating the stack needs the memory manager
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    let guard = MEMORY_MANAGER.lock();
+    let id = interrupts::without_interrupts(|| {
+        TASK_MANAGER.write().spawn_closure(stack, || 5).expect("could not spawn").read().id
+    });
+    drop(guard);
+    assert_eq!(join(id), Ok(5));
+}
+
+#[test_case]
+fn test_reap_unstarted() {
+    use x86_64::instructions::interrupts;
+    use crate::memory::paging::MEMORY_MANAGER;
+
+    let used_frames = || MEMORY_MANAGER.lock().as_ref()
+        .expect("memory manager not installed")
+        .frame_allocator.used_frames();
+    let used = used_frames();
+
+    // a proc reaped before it first ran still owns its closure, kernel
+    // stack and address space
+    let captured = Arc::new(());
+    let space = {
+        let mut guard = MEMORY_MANAGER.lock();
+        let manager = guard.as_mut().expect("memory manager not installed");
+        AddressSpace::new(&mut manager.frame_allocator).expect("could not create address space")
+    };
+    let stack = KernelStack::new().expect("could not allocate kernel stack");
+    let id = interrupts::without_interrupts(|| {
+        let mut manager = TASK_MANAGER.write();
+        let value = captured.clone();
+        let proc = manager.spawn_closure(stack, move || {
+            drop(value);
+            0
+        }).expect("could not spawn");
+        let mut proc = proc.write();
+        proc.set_address_space(space);
+        proc.exit_code = Some(3);
+        proc.state = ProcState::Zombie;
+        proc.id
+    });
+
+    assert_eq!(join(id), Ok(3));
+    assert_eq!(Arc::strong_count(&captured), 1);
+    assert_eq!(used_frames(), used);
 }
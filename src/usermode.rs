@@ -0,0 +1,64 @@
+use x86_64::VirtAddr;
+
+use crate::gdt;
+
+// Interrupts enabled, reserved bit 1 set
+const USER_RFLAGS: u64 = 0x202;
+
+// Drops to ring 3 at entry with the given stack. The current kernel stack
+// is abandoned; interrupts from user mode arrive on the stack set with
+// gdt::set_kernel_stack.
+pub unsafe fn enter_user_mode(entry: VirtAddr, user_stack: VirtAddr) -> ! {
+    let (code_selector, data_selector) = gdt::user_selectors();
+
+    asm!("
+        mov ds, $0
+        mov es, $0
+        push $0
+        push $1
+        push $2
+        push $3
+        push $4
+        iretq"
+         : 
+         : "r"(data_selector.0 as u64), "r"(user_stack.as_u64()), "r"(USER_RFLAGS),
+           "r"(code_selector.0 as u64), "r"(entry.as_u64())
+         : "memory"
+         : "intel", "volatile");
+
+    unreachable!("returned from iretq to user mode");
+}
+
+#[test_case]
+fn test_enter_user_mode() {
+    use x86_64::instructions::interrupts;
+    use crate::task::TASK_MANAGER;
+    use crate::syscall::{user_address_space, spawn_user};
+    use crate::{exception, scheduler, pit};
+
+    // int3; jmp $
+    let code: [u8; 3] = [0xcc, 0xeb, 0xfe];
+    let (space, code_addr) = user_address_space(&[&code]);
+
+    exception::expect_exception(3, 0);
+    let id = spawn_user(space, code_addr);
+
+    let start = pit::ticks();
+    let report = loop {
+        if let Some(report) = exception::take_report() {
+            break report;
+        }
+        assert!(pit::ticks() - start < 1000, "user task never hit its breakpoint");
+        scheduler::sleep_ms(5);
+    };
+
+    // the breakpoint was raised from ring 3
+    assert_eq!(report.code_segment & 3, 3);
+    assert_eq!(report.instruction_pointer, code_addr + 1u64);
+
+    // freeing the proc frees its address space
+    interrupts::without_interrupts(|| {
+        TASK_MANAGER.write().remove(id);
+    });
+    crate::task::free_reaped();
+}
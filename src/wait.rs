@@ -0,0 +1,170 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+
+use x86_64::instructions::interrupts;
+
+use crate::task::{self, ProcState};
+use crate::scheduler;
+
+// A list of procs blocked until some event happens. Locks are only taken
+// with interrupts disabled so the wake functions can be called from
+// interrupt handlers.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<usize>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Blocks the current proc until condition returns true. The condition
+    // is checked with interrupts disabled and rechecked after every wakeup.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        loop {
+            let done = interrupts::without_interrupts(|| {
+                if condition() {
+                    return true;
+                }
+
+                if scheduler::enabled() {
+                    self.waiters.lock().push_back(task::current_id());
+                    task::block_current(ProcState::Blocked, 0);
+                }
+                false
+            });
+
+            if done {
+                return;
+            }
+
+            if !scheduler::enabled() {
+                // nothing to switch to yet, wait for the next interrupt
+                x86_64::instructions::hlt();
+            }
+        }
+    }
+
+    pub fn wake_one(&self) {
+        interrupts::without_interrupts(|| {
+            if let Some(id) = self.waiters.lock().pop_front() {
+                task::wake(id);
+            }
+        });
+    }
+
+    pub fn wake_all(&self) {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            while let Some(id) = waiters.pop_front() {
+                task::wake(id);
+            }
+        });
+    }
+}
+
+const INPUT_BUFFER_SIZE: usize = 256;
+
+struct ByteBuffer {
+    data: [u8; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+// Bytes produced by an interrupt handler and consumed by tasks
+pub struct InputQueue {
+    buffer: Mutex<ByteBuffer>,
+    waiters: WaitQueue,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        InputQueue {
+            buffer: Mutex::new(ByteBuffer {
+                data: [0; INPUT_BUFFER_SIZE],
+                head: 0,
+                len: 0,
+            }),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Called from interrupt handlers. Drops the byte if the buffer is full.
+    pub fn push(&self, byte: u8) {
+        interrupts::without_interrupts(|| {
+            let mut buffer = self.buffer.lock();
+            if buffer.len < INPUT_BUFFER_SIZE {
+                let tail = (buffer.head + buffer.len) % INPUT_BUFFER_SIZE;
+                buffer.data[tail] = byte;
+                buffer.len += 1;
+            }
+        });
+        self.waiters.wake_all();
+    }
+
+    pub fn try_pop(&self) -> Option<u8> {
+        interrupts::without_interrupts(|| {
+            let mut buffer = self.buffer.lock();
+            if buffer.len == 0 {
+                return None;
+            }
+            let byte = buffer.data[buffer.head];
+            buffer.head = (buffer.head + 1) % INPUT_BUFFER_SIZE;
+            buffer.len -= 1;
+            Some(byte)
+        })
+    }
+
+    // Blocks until a byte is available
+    pub fn pop(&self) -> u8 {
+        let mut byte = None;
+        self.waiters.wait_until(|| {
+            byte = self.try_pop();
+            byte.is_some()
+        });
+        byte.unwrap()
+    }
+}
+
+#[test_case]
+fn test_wait_queue() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::memory::kernel_stack::KernelStack;
+
+    let queue = Arc::new(WaitQueue::new());
+    let flag = Arc::new(AtomicBool::new(false));
+
+    let waiter = {
+        let queue = queue.clone();
+        let flag = flag.clone();
+        let stack = KernelStack::new().expect("could not allocate kernel stack");
+        interrupts::without_interrupts(|| {
+            task::TASK_MANAGER.write().spawn_closure(stack, move || {
+                queue.wait_until(|| flag.load(Ordering::SeqCst));
+                7
+            }).expect("could not spawn waiter").read().id
+        })
+    };
+
+    // let the waiter block
+    scheduler::sleep_ms(20);
+    assert_eq!(task::TASK_MANAGER.read().get(waiter).unwrap().read().state,
+               ProcState::Blocked);
+
+    flag.store(true, Ordering::SeqCst);
+    queue.wake_all();
+    assert_eq!(task::join(waiter), Ok(7));
+}
+
+#[test_case]
+fn test_input_queue() {
+    let input = InputQueue::new();
+    input.push(b'a');
+    input.push(b'b');
+    assert_eq!(input.pop(), b'a');
+    assert_eq!(input.try_pop(), Some(b'b'));
+    assert_eq!(input.try_pop(), None);
+}
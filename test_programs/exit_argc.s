@@ -0,0 +1,32 @@
+# Exits with argc + 40 if argv[0] starts with 'p', the .bss is zeroed and
+# the .data initialized. It is linked above the kernel's level 4 entries so
+# it can run in its own address space. Rebuild with:
+#   as exit_argc.s -o exit_argc.o
+#   ld -static -nostdlib -z noexecstack -Ttext=0x100000400000 exit_argc.o -o exit_argc.elf
+#   strip exit_argc.elf
+    .intel_syntax noprefix
+    .global _start
+
+    .text
+_start:
+    mov rax, [rsp]
+    mov rbx, [rsp + 8]
+    cmp byte ptr [rbx], 'p'
+    jne fail
+    add rax, [rip + counter]
+    add rax, [rip + value]
+    mov rdi, rax
+    mov rax, 1
+    syscall
+fail:
+    mov rdi, -1
+    mov rax, 1
+    syscall
+
+    .data
+value:
+    .quad 40
+
+    .bss
+counter:
+    .skip 8192